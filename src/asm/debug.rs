@@ -0,0 +1,152 @@
+use super::SymbolType;
+use crate::vm::snapshot::{Reader, SnapshotError, Writer};
+
+/// Version of the debug section layout
+pub const DEBUG_VERSION: u16 = 1;
+
+/// Where an instruction came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineInfo {
+    pub addr: u32,
+    pub file: u16, // index in `DebugInfo::files`
+    pub line: u32,
+    pub column: u16,
+}
+
+/// The debug section of a PIE file: source positions of the instructions
+/// and the symbol table.
+///
+/// Layout, big endian: u16 version, the file names, the line table (per
+/// entry: u32 address, u16 file, u32 line, u16 column), the symbols
+/// (per symbol: u8 type, u32 value, name). Lists and strings are prefixed
+/// with their u64 length.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub lines: Vec<LineInfo>,                    // by address
+    pub symbols: Vec<(String, SymbolType, u32)>, // name, type, value
+}
+
+impl DebugInfo {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.u16(DEBUG_VERSION);
+        w.u64(self.files.len() as u64);
+        for f in &self.files {
+            w.slice(f.as_bytes());
+        }
+        w.u64(self.lines.len() as u64);
+        for l in &self.lines {
+            w.u32(l.addr);
+            w.u16(l.file);
+            w.u32(l.line);
+            w.u16(l.column);
+        }
+        w.u64(self.symbols.len() as u64);
+        for (name, type_, value) in &self.symbols {
+            w.u8(match type_ {
+                SymbolType::Label => 0,
+                SymbolType::HostFunction => 1,
+                SymbolType::Data => 2,
+            });
+            w.u32(*value);
+            w.slice(name.as_bytes());
+        }
+        w.bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<DebugInfo, SnapshotError> {
+        let mut r = Reader::new(bytes);
+        let version = r.u16()?;
+        if version != DEBUG_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let string = |r: &mut Reader| {
+            String::from_utf8(r.vec()?).map_err(|_| SnapshotError::Invalid("name"))
+        };
+        let mut debug = DebugInfo::default();
+        for _ in 0..r.u64()? {
+            debug.files.push(string(&mut r)?);
+        }
+        for _ in 0..r.u64()? {
+            let (addr, file, line, column) = (r.u32()?, r.u16()?, r.u32()?, r.u16()?);
+            debug.lines.push(LineInfo {
+                addr,
+                file,
+                line,
+                column,
+            });
+        }
+        for _ in 0..r.u64()? {
+            let type_ = match r.u8()? {
+                0 => SymbolType::Label,
+                1 => SymbolType::HostFunction,
+                2 => SymbolType::Data,
+                _ => return Err(SnapshotError::Invalid("symbol type")),
+            };
+            let value = r.u32()?;
+            debug.symbols.push((string(&mut r)?, type_, value));
+        }
+        Ok(debug)
+    }
+
+    pub fn line_at(&self, addr: usize) -> Option<&LineInfo> {
+        self.lines.iter().find(|l| l.addr as usize == addr)
+    }
+
+    /// `file:line:column` of the instruction at `addr`
+    pub fn location(&self, addr: usize) -> Option<String> {
+        let l = self.line_at(addr)?;
+        Some(format!("{}:{}:{}", self.file(l), l.line, l.column))
+    }
+
+    /// `file:line` of the instruction at `addr`
+    pub fn source_line(&self, addr: usize) -> Option<String> {
+        let l = self.line_at(addr)?;
+        Some(format!("{}:{}", self.file(l), l.line))
+    }
+
+    fn file(&self, l: &LineInfo) -> &str {
+        self.files.get(l.file as usize).map_or("?", |f| f.as_str())
+    }
+
+    /// the code label at exactly `addr`
+    pub fn label_at(&self, addr: usize) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|(_, t, v)| *t == SymbolType::Label && *v as usize == addr)
+            .map(|(name, _, _)| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_debug_info_bytes() {
+        let debug = DebugInfo {
+            files: vec!["a.asm".to_string()],
+            lines: vec![LineInfo {
+                addr: 64,
+                file: 0,
+                line: 3,
+                column: 5,
+            }],
+            symbols: vec![
+                ("top".to_string(), SymbolType::Label, 64),
+                ("msg".to_string(), SymbolType::Data, 0),
+            ],
+        };
+        let bytes = debug.to_bytes();
+        assert_eq!(DebugInfo::parse(&bytes), Ok(debug.clone()));
+        assert_eq!(debug.location(64), Some("a.asm:3:5".to_string()));
+        assert_eq!(debug.location(68), None);
+        assert_eq!(debug.source_line(64), Some("a.asm:3".to_string()));
+        assert_eq!(debug.label_at(64), Some("top"));
+        assert_eq!(debug.label_at(0), None);
+        assert_eq!(
+            DebugInfo::parse(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::instruction::Opcode;
+pub mod debug;
 pub mod parser_directive;
 pub mod parser_instruction;
 pub mod parser_label;
@@ -9,16 +10,23 @@ pub mod parser_reg;
 
 use crate::asm::parser_program::{program, Program};
 
+use self::debug::{DebugInfo, LineInfo};
 use self::parser_instruction::AssemblerInstruction;
 
-pub const PIE_HEADER_PREFIX: [u8; 4] = [0x7e, 'P' as u8, 'I' as u8, 'E' as u8];
+pub const PIE_HEADER_PREFIX: [u8; 4] = [0x7e, b'P', b'I', b'E'];
 pub const PIE_HEADER_LENGTH: usize = 64;
+/// offset in the header of the (big endian u32) length of the imports section
+pub const PIE_IMPORTS_LENGTH_OFFSET: usize = 4;
+/// offset in the header of the (big endian u32) length of the debug section,
+/// 0 when there is none
+pub const PIE_DEBUG_LENGTH_OFFSET: usize = 8;
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Op { code: Opcode },
     Reg { reg: u8 },
     IntegerOperand { i: i32 },
+    FloatOperand { f: f64 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
@@ -29,12 +37,16 @@ pub enum Token {
 pub struct Assembler {
     pub phase: AssemblerPhase,
     pub symbols: SymbolTable,
-    pub ro: Vec<u8>,       // read-only data section for constants
-    pub bytecode: Vec<u8>, // compiled bytecode
+    pub ro: Vec<u8>,                       // read-only data section for constants
+    pub lines: Vec<(usize, usize, usize)>, // address, source line and column of each instruction
+    pub debug: bool,                       // append a debug section to the program
+    pub file: String,                      // name of the source, for the debug section
+    pub bytecode: Vec<u8>,                 // compiled bytecode
+    pub imports: Vec<HostImport>,
 
+    floats: Vec<(f64, u16)>, // float constants in ro
     sections: Vec<AssemblerSection>,
     current_section: Option<AssemblerSection>,
-    current_instruction: usize,
     errors: Vec<AssemblerError>,
 }
 
@@ -47,9 +59,15 @@ pub enum AssemblerSection {
 #[derive(Debug, Clone)]
 pub enum AssemblerError {
     ParseError(String),
-    NoSegmentFor(usize, String),     // where, what
-    SymbolRedeclared(usize, String), // where, what
-    UnknownDirective(usize, String), // where, what
+    NoSegmentFor(usize, String),            // where, what
+    SymbolRedeclared(usize, String),        // where, what
+    UnknownDirective(usize, String),        // where, what
+    NotAHostFunction(usize, String),        // where, what
+    UnknownSymbol(usize, String),           // where, what
+    BranchOutOfRange(usize, String),        // where, what
+    ReadOnlyDataFull(usize, String), // where, what: a constant past the 64 KiB LOADF can address
+    ImmediateOutOfRange(usize, usize, i32), // line, column, value not fitting in an i16
+    ImportNameTooLong(String),       // a .host name longer than its 255-byte length prefix allows
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -65,9 +83,11 @@ pub struct Symbol {
     type_: SymbolType,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SymbolType {
     Label,
+    HostFunction,
+    Data, // a label in .data, its offset is into ro
 }
 
 #[derive(Debug)]
@@ -75,16 +95,47 @@ pub struct SymbolTable {
     symbols: Vec<Symbol>,
 }
 
+/// A host function the program calls with `syscall`, declared with `name: .host #id`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostImport {
+    pub id: u16,
+    pub name: String,
+}
+
+/// The parsed header of a PIE file.
+///
+/// Layout: the 4 prefix bytes, the length of the imports section, the length
+/// of the debug section and zero padding up to PIE_HEADER_LENGTH; then the
+/// imports section (per import: u16 id, u8 name length, name), then the
+/// code, then the debug section if there is one.
+#[derive(Debug, PartialEq)]
+pub struct PieHeader {
+    pub imports: Vec<HostImport>,
+    pub code_offset: usize,
+    pub code_end: usize, // where the debug section starts, or the end of the file
+    pub debug: Option<DebugInfo>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Assembler {
     pub fn new() -> Assembler {
         Assembler {
             phase: AssemblerPhase::First,
             symbols: SymbolTable::new(),
             ro: vec![],
+            lines: vec![],
+            debug: false,
+            file: "<input>".to_string(),
             bytecode: vec![],
+            imports: vec![],
+            floats: vec![],
             sections: vec![],
             current_section: None,
-            current_instruction: 0,
             errors: vec![],
         }
     }
@@ -92,15 +143,23 @@ impl Assembler {
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
         match program(raw) {
             Ok((_rest, prog)) => {
-                let mut program = self.write_pie_header();
                 self.process_first_phase(&prog);
-                if self.errors.is_empty() && self.sections.contains(&AssemblerSection::Code) {
-                    let mut body = self.process_second_phase(&prog);
-                    program.append(&mut body);
-                    Ok(program)
-                } else {
-                    Err(self.errors.clone())
+                if !self.errors.is_empty() || !self.sections.contains(&AssemblerSection::Code) {
+                    return Err(self.errors.clone());
                 }
+                let mut body = self.process_second_phase(&prog);
+                if !self.errors.is_empty() {
+                    return Err(self.errors.clone());
+                }
+                let mut program = self.write_pie_header();
+                program.append(&mut body);
+                if self.debug {
+                    let section = self.debug_info().to_bytes();
+                    program[PIE_DEBUG_LENGTH_OFFSET..PIE_DEBUG_LENGTH_OFFSET + 4]
+                        .copy_from_slice(&(section.len() as u32).to_be_bytes());
+                    program.extend_from_slice(&section);
+                }
+                Ok(program)
             }
             Err(e) => {
                 println!("Error assembling: {}", e);
@@ -110,7 +169,15 @@ impl Assembler {
     }
 
     fn process_first_phase(&mut self, p: &Program) {
+        let mut code_labels = vec![];
+        let mut code_len = 0;
         for (idx, i) in p.instructions.iter().enumerate() {
+            if i.opcode.is_some() {
+                if let Some(label) = i.label_name() {
+                    code_labels.push((label, code_len));
+                }
+                code_len += 4;
+            }
             if let Some(label) = i.label_name() {
                 match self.current_section {
                     None => self
@@ -134,7 +201,8 @@ impl Assembler {
             match i.directive_name() {
                 Some(directive) if i.operand1.is_some() && i.label_name().is_some() => {
                     match directive.as_str() {
-                        "asciiz" => self.do_asciiz(&i),
+                        "asciiz" => self.do_asciiz(i),
+                        "host" => self.do_host(i),
                         _ => self
                             .errors
                             .push(AssemblerError::UnknownDirective(idx * 4, directive)),
@@ -156,14 +224,66 @@ impl Assembler {
                 _ => {}
             }
         }
+        // code comes after the header, whose size is known once all the
+        // imports have been seen
+        let code_offset = self.code_offset() as u32;
+        for (label, offset) in code_labels {
+            self.symbols.set_symbol_offset(&label, code_offset + offset);
+        }
         self.phase = AssemblerPhase::Second;
     }
 
     fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
         let mut prog = vec![];
-        for i in &p.instructions {
+        let code_offset = self.code_offset();
+        self.lines.clear();
+        for (idx, i) in p.instructions.iter().enumerate() {
             if let Some(Token::Op { code }) = i.opcode {
+                let addr = code_offset + prog.len();
+                let (line, column) = p.positions[idx];
+                self.lines.push((addr, line, column));
+                let branch = match (&i.operand1, code.is_relative_branch()) {
+                    (Some(Token::LabelUsage { name }), true) => {
+                        match self.branch_offset(idx, addr, name) {
+                            Some(offset) => Some(offset),
+                            None => continue,
+                        }
+                    }
+                    _ => None,
+                };
+                if let (true, Some(Token::IntegerOperand { i: imm })) =
+                    (i.select_opcode(code).is_immediate(), &i.operand2)
+                {
+                    if i16::try_from(*imm).is_err() {
+                        self.errors
+                            .push(AssemblerError::ImmediateOutOfRange(line, column, *imm));
+                    }
+                }
+                if let (Opcode::SYSCALL, Some(Token::LabelUsage { name })) = (code, &i.operand1) {
+                    if self.symbols.symbol_type(name) != Some(&SymbolType::HostFunction) {
+                        self.errors
+                            .push(AssemblerError::NotAHostFunction(idx * 4, name.clone()));
+                    }
+                }
                 let mut bytes = i.to_bytes(&self.symbols);
+                // float literals don't fit in an instruction, they go to ro
+                // and the instruction gets their offset
+                let float = match &i.operand2 {
+                    Some(Token::FloatOperand { f }) => Some(*f),
+                    Some(Token::IntegerOperand { i }) if code == Opcode::LOADF => Some(*i as f64),
+                    _ => None,
+                };
+                if let Some(f) = float {
+                    match self.float_constant(f) {
+                        Some(offset) => bytes[2..4].copy_from_slice(&offset.to_be_bytes()),
+                        None => self
+                            .errors
+                            .push(AssemblerError::ReadOnlyDataFull(idx * 4, f.to_string())),
+                    }
+                }
+                if let Some(offset) = branch {
+                    bytes[1..3].copy_from_slice(&offset.to_be_bytes());
+                }
                 prog.append(&mut bytes);
             }
         }
@@ -176,19 +296,129 @@ impl Assembler {
             return;
         }
         if let Some(Token::String { name }) = &i.operand1 {
-            self.symbols
-                .set_symbol_offset(&i.label_name().unwrap(), self.ro.len() as u32);
+            let label = i.label_name().unwrap();
+            self.symbols.set_symbol_offset(&label, self.ro.len() as u32);
+            self.symbols.set_symbol_type(&label, SymbolType::Data);
             name.as_bytes().iter().for_each(|b| self.ro.push(*b));
             self.ro.push(0);
         }
     }
 
+    /// the offset of `name` from the branch instruction at `addr`
+    fn branch_offset(&mut self, idx: usize, addr: usize, name: &str) -> Option<i16> {
+        let target = match self.symbols.symbol_value(name) {
+            Some(target) => target as i64,
+            None => {
+                self.errors
+                    .push(AssemblerError::UnknownSymbol(idx * 4, name.to_string()));
+                return None;
+            }
+        };
+        match i16::try_from(target - addr as i64) {
+            Ok(offset) => Some(offset),
+            Err(_) => {
+                self.errors
+                    .push(AssemblerError::BranchOutOfRange(idx * 4, name.to_string()));
+                None
+            }
+        }
+    }
+
+    /// returns the offset in ro of a f64 constant, adding it if needed, or
+    /// None if ro is already too large for an instruction to address it
+    fn float_constant(&mut self, f: f64) -> Option<u16> {
+        match self.floats.iter().find(|(v, _)| v.to_bits() == f.to_bits()) {
+            Some((_, offset)) => Some(*offset),
+            None => {
+                let offset = u16::try_from(self.ro.len()).ok()?;
+                self.ro.extend_from_slice(&f.to_be_bytes());
+                self.floats.push((f, offset));
+                Some(offset)
+            }
+        }
+    }
+
+    fn do_host(&mut self, i: &AssemblerInstruction) {
+        // checked by the caller: this is in a label and operand1.is_some()
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+        let name = i.label_name().unwrap();
+        match &i.operand1 {
+            Some(Token::IntegerOperand { i: id }) if (0..=u16::MAX as i32).contains(id) => {
+                // the imports section stores the name's length in one byte
+                if name.len() > u8::MAX as usize {
+                    self.errors.push(AssemblerError::ImportNameTooLong(name));
+                    return;
+                }
+                self.symbols.set_symbol_offset(&name, *id as u32);
+                self.symbols
+                    .set_symbol_type(&name, SymbolType::HostFunction);
+                self.imports.push(HostImport {
+                    id: *id as u16,
+                    name,
+                });
+            }
+            _ => self.errors.push(AssemblerError::ParseError(format!(
+                ".host {} needs an id",
+                name
+            ))),
+        }
+    }
+
+    fn imports_section(&self) -> Vec<u8> {
+        let mut imports: Vec<u8> = vec![];
+        for import in &self.imports {
+            imports.extend_from_slice(&import.id.to_be_bytes());
+            imports.push(import.name.len() as u8);
+            imports.extend_from_slice(import.name.as_bytes());
+        }
+        imports
+    }
+
+    /// what the debug section says about the last program assembled
+    pub fn debug_info(&self) -> DebugInfo {
+        DebugInfo {
+            files: vec![self.file.clone()],
+            lines: self
+                .lines
+                .iter()
+                .map(|&(addr, line, column)| LineInfo {
+                    addr: addr as u32,
+                    file: 0,
+                    line: line as u32,
+                    column: column as u16,
+                })
+                .collect(),
+            symbols: self
+                .symbols
+                .symbols
+                .iter()
+                .map(|sym| (sym.name.clone(), sym.type_, sym.offset))
+                .collect(),
+        }
+    }
+
+    /// source line of the instruction at `addr`
+    pub fn line_at(&self, addr: usize) -> Option<usize> {
+        self.lines
+            .iter()
+            .find(|&&(a, _, _)| a == addr)
+            .map(|&(_, line, _)| line)
+    }
+
+    /// where the code starts in the PIE file
+    fn code_offset(&self) -> usize {
+        PIE_HEADER_LENGTH + self.imports_section().len()
+    }
+
     fn write_pie_header(&self) -> Vec<u8> {
+        let mut imports = self.imports_section();
         let mut header: Vec<u8> = vec![];
         PIE_HEADER_PREFIX.iter().for_each(|b| header.push(*b));
-        (header.len()..PIE_HEADER_LENGTH)
-            .into_iter()
-            .for_each(|_| header.push(0u8));
+        header.extend_from_slice(&(imports.len() as u32).to_be_bytes());
+        (header.len()..PIE_HEADER_LENGTH).for_each(|_| header.push(0u8));
+        header.append(&mut imports);
         header
     }
 }
@@ -203,6 +433,12 @@ impl Symbol {
     }
 }
 
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymbolTable {
     pub fn new() -> SymbolTable {
         SymbolTable { symbols: vec![] }
@@ -233,10 +469,96 @@ impl SymbolTable {
             }
         }
     }
+
+    pub fn symbol_type(&self, s: &str) -> Option<&SymbolType> {
+        self.symbols
+            .iter()
+            .find(|sym| sym.name == s)
+            .map(|sym| &sym.type_)
+    }
+
+    /// names of the labels, host functions and data
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.symbols.iter().map(|sym| sym.name.as_str())
+    }
+
+    /// the code label at or closest before `pc`, with how far `pc` is past it
+    pub fn code_label_at(&self, pc: usize) -> Option<(&str, usize)> {
+        self.symbols
+            .iter()
+            .filter(|sym| sym.type_ == SymbolType::Label && sym.offset as usize <= pc)
+            .max_by_key(|sym| sym.offset)
+            .map(|sym| (sym.name.as_str(), pc - sym.offset as usize))
+    }
+
+    pub fn set_symbol_type(&mut self, s: &String, type_: SymbolType) {
+        if let Some(sym) = self.symbols.iter_mut().find(|sym| sym.name == *s) {
+            sym.type_ = type_;
+        }
+    }
+}
+
+impl PieHeader {
+    /// returns None if the bytes don't start with a well formed header
+    pub fn parse(bytes: &[u8]) -> Option<PieHeader> {
+        if bytes.len() < PIE_HEADER_LENGTH || bytes[..PIE_HEADER_PREFIX.len()] != PIE_HEADER_PREFIX
+        {
+            return None;
+        }
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&bytes[PIE_IMPORTS_LENGTH_OFFSET..PIE_IMPORTS_LENGTH_OFFSET + 4]);
+        let code_offset = PIE_HEADER_LENGTH + u32::from_be_bytes(len) as usize;
+        len.copy_from_slice(&bytes[PIE_DEBUG_LENGTH_OFFSET..PIE_DEBUG_LENGTH_OFFSET + 4]);
+        let code_end = bytes.len().checked_sub(u32::from_be_bytes(len) as usize)?;
+        if code_end < code_offset {
+            return None;
+        }
+        let debug = match code_end < bytes.len() {
+            true => Some(DebugInfo::parse(&bytes[code_end..]).ok()?),
+            false => None,
+        };
+        let section = bytes.get(PIE_HEADER_LENGTH..code_offset)?;
+        let mut imports = vec![];
+        let mut i = 0;
+        while i < section.len() {
+            let entry = section.get(i..i + 3)?;
+            let id = u16::from_be_bytes([entry[0], entry[1]]);
+            let name = section.get(i + 3..i + 3 + entry[2] as usize)?;
+            imports.push(HostImport {
+                id,
+                name: String::from_utf8(name.to_vec()).ok()?,
+            });
+            i += 3 + entry[2] as usize;
+        }
+        Some(PieHeader {
+            imports,
+            code_offset,
+            code_end,
+            debug,
+        })
+    }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::vm::{VMFault, VM};
+
+    /// loads and runs an assembled test program for a bounded number of
+    /// steps, with its host functions stubbed and its output kept; with the
+    /// jit feature it's also checked against the interpreter
+    fn run(asm: &Assembler, program: Vec<u8>) -> VM {
+        let mut vm = VM::new();
+        for import in &asm.imports {
+            vm.register_syscall(import.id, &import.name, |_| Ok(0));
+        }
+        vm.load(program, asm.ro.clone()).unwrap();
+        vm.fuel = Some(10_000);
+        vm.output = Some(vec![]);
+        crate::vm::tests::run(&mut vm);
+        vm
+    }
+
     #[test]
     fn test_symbol_table() {
         let mut st = SymbolTable::new();
@@ -255,6 +577,11 @@ mod tests {
         lab:inc $0
         prts @test";
         let program: Vec<u8> = asm.assemble(code).unwrap();
+        assert_eq!(asm.symbols.symbol_type("test"), Some(&SymbolType::Data));
+        assert_eq!(
+            asm.symbols.code_label_at(PIE_HEADER_LENGTH + 12),
+            Some(("lab", 4))
+        );
         assert_eq!(
             program[PIE_HEADER_LENGTH..], // TODO check header
             vec![
@@ -276,6 +603,9 @@ mod tests {
                 0,
             ]
         );
+        let vm = run(&asm, program);
+        assert_eq!((vm.regs[0], vm.regs[1]), (101, 99));
+        assert_eq!(vm.output, Some(b"hi\n".to_vec()));
     }
 
     #[test]
@@ -286,12 +616,229 @@ mod tests {
                 ".data
             str: .asciiz 'Test String'
             .code
+            prts @str
             hlt
             ",
             )
             .unwrap();
-        assert_eq!(asm.symbols.has_symbol(&String::from("str")), true);
+        assert!(asm.symbols.has_symbol(&String::from("str")));
         assert_eq!(asm.symbols.symbol_value("str").unwrap(), 0);
         assert_eq!(asm.ro, "Test String\0".as_bytes());
+        assert_eq!(run(&asm, prog).output, Some(b"Test String\n".to_vec()));
+    }
+
+    #[test]
+    fn test_host_imports() {
+        let mut asm = Assembler::new();
+        let prog = asm
+            .assemble(
+                ".data
+            time: .host #2
+            .code
+            syscall @time
+            syscall #5",
+            )
+            .unwrap();
+        let header = PieHeader::parse(&prog).unwrap();
+        assert_eq!(
+            header.imports,
+            vec![HostImport {
+                id: 2,
+                name: "time".to_string()
+            }]
+        );
+        assert_eq!(header.code_offset, PIE_HEADER_LENGTH + 7);
+        assert_eq!(
+            prog[header.code_offset..],
+            [
+                Opcode::SYSCALL as u8,
+                0,
+                2,
+                0,
+                Opcode::SYSCALL as u8,
+                0,
+                5,
+                0
+            ]
+        );
+        let code_offset = header.code_offset;
+        assert_eq!(
+            run(&asm, prog).fault,
+            Some(VMFault::UnknownSyscall(code_offset + 4, 5))
+        );
+        assert!(Assembler::new()
+            .assemble(
+                ".data
+            str: .asciiz 'hi'
+            .code
+            syscall @str",
+            )
+            .is_err());
+
+        let long = "f".repeat(256);
+        let errors = Assembler::new()
+            .assemble(&format!(".data\n{}: .host #1\n.code\nhlt", long))
+            .unwrap_err();
+        assert!(matches!(&errors[..], [AssemblerError::ImportNameTooLong(n)] if *n == long));
+        let longest = format!(".data\n{}: .host #1\n.code\nhlt", "f".repeat(255));
+        assert!(Assembler::new().assemble(&longest).is_ok());
+    }
+
+    #[test]
+    fn test_float_constants() {
+        let mut asm = Assembler::new();
+        let prog = asm
+            .assemble(
+                ".data
+            str: .asciiz 'x'
+            .code
+            loadf $0 #2.5
+            loadf $1 #-1.
+            loadf $2 #2.5
+            loadf $3 #3",
+            )
+            .unwrap();
+        assert_eq!(
+            prog[PIE_HEADER_LENGTH..],
+            [
+                Opcode::LOADF as u8,
+                0,
+                0,
+                2,
+                Opcode::LOADF as u8,
+                1,
+                0,
+                10,
+                Opcode::LOADF as u8,
+                2,
+                0,
+                2,
+                Opcode::LOADF as u8,
+                3,
+                0,
+                18,
+            ]
+        );
+        assert_eq!(asm.ro[2..10], 2.5f64.to_be_bytes());
+        assert_eq!(asm.ro[18..], 3f64.to_be_bytes());
+        assert_eq!(run(&asm, prog).fregs[..4], [2.5, -1., 2.5, 3.]);
+
+        // LOADF can't reach past 64 KiB of ro
+        let big = format!(
+            ".data\nbig: .asciiz '{}'\n.code\nloadf $0 #1.5",
+            "x".repeat(u16::MAX as usize)
+        );
+        let errors = Assembler::new().assemble(&big).unwrap_err();
+        assert!(matches!(&errors[..], [AssemblerError::ReadOnlyDataFull(_, f)] if f == "1.5"));
+    }
+
+    #[test]
+    fn test_immediate_range() {
+        let mut asm = Assembler::new();
+        let prog = asm
+            .assemble(".code\naddi $0 #32767\nsub $0 #-32768\nload $1 #40000")
+            .unwrap();
+        let vm = run(&asm, prog);
+        assert_eq!((vm.regs[0], vm.regs[1]), (65535, 40000));
+        let errors = Assembler::new()
+            .assemble(".code\nhlt\n  addi $0 #40000\nlt $1 #-32769")
+            .unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [
+                AssemblerError::ImmediateOutOfRange(3, 3, 40000),
+                AssemblerError::ImmediateOutOfRange(4, 1, -32769)
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_code_labels() {
+        let mut asm = Assembler::new();
+        let prog = asm
+            .assemble(
+                ".data
+            now: .host #1
+            .code
+            load $0 @end
+            top: dec $1
+            bne @top
+            br @end
+            end: hlt",
+            )
+            .unwrap();
+        let start = PIE_HEADER_LENGTH + 6;
+        assert_eq!(asm.symbols.symbol_value("top"), Some(start as u32 + 4));
+        assert_eq!(asm.symbols.symbol_value("end"), Some(start as u32 + 16));
+        let top = start + 4;
+        assert_eq!(asm.line_at(top), Some(5));
+        assert_eq!(asm.lines[4], (start + 16, 8, 13));
+        assert_eq!(asm.symbols.code_label_at(top + 8), Some(("top", 8)));
+        assert_eq!(asm.symbols.code_label_at(start), None);
+        assert_eq!(
+            prog[start..],
+            [
+                Opcode::LOAD as u8,
+                0,
+                0,
+                start as u8 + 16,
+                Opcode::DEC as u8,
+                1,
+                0,
+                0,
+                Opcode::BNE as u8,
+                0xff,
+                0xfc, // -4
+                0,
+                Opcode::BR as u8,
+                0,
+                4,
+                0,
+                Opcode::HLT as u8,
+                0,
+                0,
+                0,
+            ]
+        );
+        // bne loops while the equality flag is clear, so this runs out of fuel
+        let vm = run(&asm, prog);
+        assert_eq!((vm.regs[0], vm.fuel), (start as i32 + 16, Some(0)));
+        assert!(Assembler::new().assemble(".code\nbr @nowhere").is_err());
+    }
+    #[test]
+    fn test_debug_section() {
+        let source = ".data\nmsg: .asciiz 'hi'\n.code\nload $0 #1\ntop: dec $0\nhlt";
+        let stripped = Assembler::new().assemble(source).unwrap();
+        let mut asm = Assembler::new();
+        asm.debug = true;
+        asm.file = "t.asm".to_string();
+        let prog = asm.assemble(source).unwrap();
+        let header = PieHeader::parse(&prog).unwrap();
+        assert_eq!(header.code_end, stripped.len());
+        assert_eq!(
+            prog[PIE_DEBUG_LENGTH_OFFSET + 4..header.code_end],
+            stripped[PIE_DEBUG_LENGTH_OFFSET + 4..]
+        );
+        let debug = header.debug.unwrap();
+        let top = asm.symbols.symbol_value("top").unwrap() as usize;
+        assert_eq!(debug.files, vec!["t.asm".to_string()]);
+        assert_eq!(debug.location(top), Some("t.asm:5:1".to_string()));
+        assert_eq!(debug.label_at(top), Some("top"));
+        assert!(debug
+            .symbols
+            .contains(&("msg".to_string(), SymbolType::Data, 0)));
+        assert!(PieHeader::parse(&stripped).unwrap().debug.is_none());
+
+        let mut vm = crate::vm::VM::new();
+        vm.load(prog, asm.ro.clone()).unwrap();
+        assert_eq!(vm.program().len(), header.code_end);
+        assert_eq!(
+            vm.describe_pc(top + 4),
+            format!("{:#x} (t.asm:6:1)", top + 4)
+        );
+        // the loaded program's header no longer claims a debug section
+        assert_eq!(PieHeader::parse(vm.program()).unwrap().code_offset, top - 4);
+        crate::vm::tests::run(&mut vm);
+        assert_eq!((vm.regs[0], vm.fault.clone()), (0, None));
     }
 }
@@ -1,6 +1,6 @@
 use nom::combinator::opt;
 use nom::{
-    branch::alt, bytes::complete::tag, character::complete::alpha1, character::complete::space0,
+    bytes::complete::tag, character::complete::alpha1, character::complete::space0,
     sequence::terminated, sequence::tuple, IResult,
 };
 
@@ -41,10 +41,10 @@ pub fn directive_all(input: &str) -> IResult<&str, AssemblerInstruction> {
         AssemblerInstruction {
             opcode: None,
             directive: Some(name),
-            label: label,
-            operand1: operand1,
-            operand2: operand2,
-            operand3: operand3,
+            label,
+            operand1,
+            operand2,
+            operand3,
         },
     ))
 }
@@ -54,6 +54,7 @@ pub fn directive(input: &str) -> IResult<&str, AssemblerInstruction> {
     directive_all(input)
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
     #[test]
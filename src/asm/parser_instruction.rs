@@ -4,12 +4,13 @@ use nom::{
 };
 
 use crate::asm::parser_directive::*;
-use crate::asm::parser_label::{label_declaration, label_usage};
+use crate::asm::parser_label::label_declaration;
 use crate::asm::parser_op::*;
 use crate::asm::parser_operand::{integer_operand, operand};
 use crate::asm::parser_reg::register;
 use crate::asm::SymbolTable;
 use crate::asm::Token;
+use crate::instruction::Opcode;
 
 #[derive(Debug, PartialEq)]
 pub struct AssemblerInstruction {
@@ -25,27 +26,37 @@ impl AssemblerInstruction {
     pub fn to_bytes(&self, st: &SymbolTable) -> Vec<u8> {
         let mut res = vec![];
         match self.opcode {
-            Some(Token::Op { code }) => res.push(code as u8),
+            Some(Token::Op { code }) => res.push(self.select_opcode(code) as u8),
             _ => {
                 println!("Non-opcode found in opcode field: {:?}", self.opcode);
                 std::process::exit(1)
             }
         };
-        for op in vec![&self.operand1, &self.operand2, &self.operand3] {
-            match op {
-                Some(op) => AssemblerInstruction::extract_operand(op, &mut res, st),
-                None => {}
-            }
+        for op in [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+        {
+            AssemblerInstruction::extract_operand(op, &mut res, st);
         }
         while res.len() < 4 {
             res.push(0); // padding
         }
-        return res;
+        res
+    }
+
+    /// `add $0 #5` is the immediate form of `add`, ADDI
+    pub fn select_opcode(&self, code: Opcode) -> Opcode {
+        match (&self.operand2, &self.operand3, code.immediate()) {
+            (Some(Token::IntegerOperand { .. }), None, Some(imm)) => imm,
+            _ => code,
+        }
     }
 
     fn extract_operand(t: &Token, res: &mut Vec<u8>, st: &SymbolTable) {
         match t {
             Token::Reg { reg } => res.push(*reg),
+            // placeholder for the ro offset the assembler fills in
+            Token::FloatOperand { .. } => res.extend_from_slice(&[0, 0]),
             Token::IntegerOperand { i } => {
                 let v = *i as u16;
                 let byte1 = v;
@@ -210,9 +221,9 @@ pub fn instruction_all(input: &str) -> IResult<&str, AssemblerInstruction> {
     ))
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use crate::instruction::Opcode;
 
     #[test]
     fn test_parse_instruction_two() {
@@ -231,4 +242,14 @@ mod tests {
             ))
         )
     }
+    #[test]
+    fn test_immediate_operands() {
+        let st = SymbolTable::new();
+        let bytes = |src| instruction(src).unwrap().1.to_bytes(&st);
+        assert_eq!(bytes("add $1 #5"), [Opcode::ADDI as u8, 1, 0, 5]);
+        assert_eq!(bytes("addi $1 #5"), [Opcode::ADDI as u8, 1, 0, 5]);
+        assert_eq!(bytes("add $1 $2 $3"), [Opcode::ADD as u8, 1, 2, 3]);
+        assert_eq!(bytes("gt $4 #-2"), [Opcode::GTI as u8, 4, 0xff, 0xfe]);
+        assert_eq!(bytes("load $1 #5"), [Opcode::LOAD as u8, 1, 0, 5]);
+    }
 }
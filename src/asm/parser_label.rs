@@ -1,10 +1,8 @@
 use nom::{
-    branch::alt, bytes::complete::tag, character::complete::alpha1, character::complete::space0,
-    sequence::terminated, sequence::tuple, IResult,
+    bytes::complete::tag, character::complete::alpha1, character::complete::space0,
+    sequence::tuple, IResult,
 };
 
-use crate::asm::parser_instruction::*;
-use crate::asm::parser_operand::operand;
 use crate::asm::Token;
 
 pub fn label_declaration(input: &str) -> IResult<&str, Token> {
@@ -27,6 +25,7 @@ pub fn label_usage(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
     #[test]
@@ -40,7 +39,7 @@ mod tests {
                 }
             ))
         );
-        assert_eq!(label_declaration(" test_ ").is_ok(), false);
+        assert!(label_declaration(" test_ ").is_err());
     }
     #[test]
     fn test_parse_label_usage() {
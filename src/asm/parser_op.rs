@@ -14,15 +14,27 @@ pub fn opcode(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
     #[test]
     fn test_opcode() {
         let r = opcode("load");
-        assert_eq!(r.is_ok(), true);
+        assert!(r.is_ok());
         let (r, token) = r.unwrap();
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(r, "");
+        for (m, code) in [
+            ("xor", Opcode::XOR),
+            ("shl", Opcode::SHL),
+            ("shr", Opcode::SHR),
+            ("sar", Opcode::SAR),
+            ("rol", Opcode::ROL),
+            ("ror", Opcode::ROR),
+            ("mod", Opcode::MOD),
+        ] {
+            assert_eq!(opcode(m).unwrap().1, Token::Op { code });
+        }
         assert_eq!(
             opcode("invalid").unwrap().1,
             Token::Op { code: Opcode::IGL }
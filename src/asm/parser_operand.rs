@@ -1,7 +1,8 @@
 use nom::bytes::complete::take_until;
 use nom::{
-    branch::alt, bytes::complete::tag, character::complete::digit1,
-    character::complete::multispace0, sequence::terminated, IResult,
+    branch::alt, bytes::complete::tag, character::complete::char, character::complete::digit0,
+    character::complete::digit1, character::complete::multispace0, combinator::opt,
+    combinator::recognize, sequence::terminated, sequence::tuple, IResult,
 };
 
 use crate::asm::parser_reg::register;
@@ -13,7 +14,7 @@ use super::parser_label::label_usage;
 pub fn integer_operand(input: &str) -> IResult<&str, Token> {
     let input = input.trim();
     let (input, _) = tag("#")(input)?;
-    let (input, i) = terminated(digit1, multispace0)(input)?;
+    let (input, i) = terminated(recognize(tuple((opt(char('-')), digit1))), multispace0)(input)?;
     Ok((
         input,
         Token::IntegerOperand {
@@ -22,6 +23,22 @@ pub fn integer_operand(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+/* recognize: #n.m where n.m is f64 with 0+ spaces around; the dot is required */
+pub fn float_operand(input: &str) -> IResult<&str, Token> {
+    let input = input.trim();
+    let (input, _) = tag("#")(input)?;
+    let (input, f) = terminated(
+        recognize(tuple((opt(char('-')), digit1, char('.'), digit0))),
+        multispace0,
+    )(input)?;
+    Ok((
+        input,
+        Token::FloatOperand {
+            f: f.parse::<f64>().unwrap(),
+        },
+    ))
+}
+
 pub fn string_operand(input: &str) -> IResult<&str, Token> {
     let input = input.trim();
     let (input, _) = tag("'")(input)?;
@@ -36,9 +53,16 @@ pub fn string_operand(input: &str) -> IResult<&str, Token> {
 }
 
 pub fn operand(input: &str) -> IResult<&str, Token> {
-    alt((integer_operand, label_usage, register, string_operand))(input)
+    alt((
+        float_operand,
+        integer_operand,
+        label_usage,
+        register,
+        string_operand,
+    ))(input)
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
     #[test]
@@ -51,8 +75,25 @@ mod tests {
             integer_operand("#10").unwrap(),
             ("", Token::IntegerOperand { i: 10 })
         );
+        assert_eq!(
+            integer_operand("#-3").unwrap(),
+            ("", Token::IntegerOperand { i: -3 })
+        );
         //assert_eq!(integer_operand("#1a").is_ok(), false);
-        assert_eq!(integer_operand("1").is_ok(), false);
+        assert!(integer_operand("1").is_err());
+    }
+    #[test]
+    fn test_parse_float_operand() {
+        assert_eq!(
+            float_operand("#1.5").unwrap(),
+            ("", Token::FloatOperand { f: 1.5 })
+        );
+        assert_eq!(
+            float_operand("#-2. ").unwrap(),
+            ("", Token::FloatOperand { f: -2.0 })
+        );
+        assert!(float_operand("#2").is_err());
+        assert_eq!(operand("#2").unwrap(), ("", Token::IntegerOperand { i: 2 }));
     }
     #[test]
     fn test_parse_string_operand() {
@@ -1,10 +1,11 @@
 use crate::asm::parser_instruction::*;
 use crate::asm::SymbolTable;
-use nom::{multi::many1, IResult};
+use nom::{error::Error, error::ErrorKind, IResult, Offset};
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
     pub instructions: Vec<AssemblerInstruction>,
+    pub positions: Vec<(usize, usize)>, // line and column of each instruction, from 1
 }
 
 impl Program {
@@ -13,20 +14,49 @@ impl Program {
         for i in &self.instructions {
             prog.append(&mut i.to_bytes(st))
         }
-        return prog;
+        prog
     }
 }
 
+/// one or more instructions, remembering where each starts
 pub fn program(input: &str) -> IResult<&str, Program> {
-    let (input, is) = many1(instruction)(input)?;
-    Ok((input, Program { instructions: is }))
+    let mut instructions = vec![];
+    let mut positions = vec![];
+    let mut rest = input;
+    let (mut line, mut line_start, mut scanned) = (1, 0, 0);
+    loop {
+        let (r, i) = match instruction(rest) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Error(_)) if !instructions.is_empty() => break,
+            Err(e) => return Err(e),
+        };
+        if r.len() == rest.len() {
+            return Err(nom::Err::Error(Error::new(rest, ErrorKind::Many1)));
+        }
+        // the instruction parser may trim the end of what it leaves, so
+        // measure from the start
+        let start = input.offset(rest.trim_start());
+        for (at, _) in input[scanned..start].match_indices('\n') {
+            line += 1;
+            line_start = scanned + at + 1;
+        }
+        scanned = start;
+        positions.push((line, start - line_start + 1));
+        instructions.push(i);
+        rest = r;
+    }
+    Ok((
+        rest,
+        Program {
+            instructions,
+            positions,
+        },
+    ))
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use crate::asm::parser_op::*;
-    use crate::asm::parser_operand::integer_operand;
-    use crate::asm::parser_reg::register;
     use crate::asm::Token;
     use crate::instruction::Opcode;
     #[test]
@@ -43,7 +73,8 @@ mod tests {
                         operand3: None,
                         directive: None,
                         label: None,
-                    }]
+                    }],
+                    positions: vec![(1, 1)],
                 }
             ))
         );
@@ -69,10 +100,15 @@ mod tests {
                             directive: None,
                             label: None,
                         }
-                    ]
+                    ],
+                    positions: vec![(1, 1), (2, 1)],
                 }
             ))
         );
+        assert_eq!(
+            program("load $0 #0\nload $1 #4\nhlt\n").map(|p| p.1.positions),
+            Ok(vec![(1, 1), (2, 1), (3, 1)])
+        );
     }
     #[test]
     fn test_program_to_bytes() {
@@ -80,7 +116,7 @@ mod tests {
         let prog = program("load $2 #100\n").unwrap().1;
         assert_eq!(prog.to_bytes(&st).len(), 4);
         assert_eq!(prog.to_bytes(&st)[0], Opcode::LOAD as u8);
-        assert_eq!(prog.to_bytes(&st)[1], 02u8);
+        assert_eq!(prog.to_bytes(&st)[1], 2u8);
         assert_eq!(prog.to_bytes(&st)[2], 0);
         assert_eq!(prog.to_bytes(&st)[3], 100u8);
     }
@@ -99,5 +135,6 @@ mod tests {
         .unwrap()
         .1;
         println!("{:#?}", prog);
+        assert_eq!(prog.positions, vec![(1, 1), (2, 9), (3, 9), (4, 9), (5, 9)]);
     }
 }
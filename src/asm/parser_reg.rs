@@ -17,14 +17,15 @@ pub fn register(input: &str) -> IResult<&str, Token> {
     ))
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
     #[test]
     fn test_parse_reg() {
-        assert_eq!(register("$0").is_ok(), true);
-        assert_eq!(register("$1").is_ok(), true);
-        assert_eq!(register("0").is_ok(), false);
-        assert_eq!(register("$a").is_ok(), false);
+        assert!(register("$0").is_ok());
+        assert!(register("$1").is_ok());
+        assert!(register("0").is_err());
+        assert!(register("$a").is_err());
         assert_eq!(register("$0").unwrap(), ("", Token::Reg { reg: 0 }));
         assert_eq!(register("$0 ").unwrap(), ("", Token::Reg { reg: 0 }));
         assert_eq!(register("$0 a").unwrap(), ("a", Token::Reg { reg: 0 }));
@@ -0,0 +1,111 @@
+use crate::asm::Assembler;
+use crate::vm::{VMExit, VM};
+use std::time::{Duration, Instant};
+
+/// (name, source) of the programs `rvm bench` runs; they loop forever and
+/// are stopped by running out of fuel
+pub const PROGRAMS: [(&str, &str); 2] = [
+    (
+        "integer loop",
+        ".code
+        load $1 #1
+        load $2 #3
+        top: add $0 $1 $0
+        mul $0 $2 $4
+        xor $4 $0 $4
+        inc $3
+        lt $3 #30000
+        beq @top
+        load $3 #0
+        br @top",
+    ),
+    (
+        "float loop",
+        ".code
+        loadf $1 #1.5
+        loadf $2 #0.5
+        top: addf $0 $1 $0
+        mulf $0 $2 $0
+        inc $3
+        lt $3 #30000
+        beq @top
+        load $3 #0
+        br @top",
+    ),
+];
+
+/// configures a VM for one of the modes
+pub type Setup = fn(&mut VM);
+
+/// (name, setup) of the ways `rvm bench` runs each program
+#[allow(unused_mut)]
+pub fn modes() -> Vec<(&'static str, Setup)> {
+    let mut modes: Vec<(&str, Setup)> = vec![
+        ("decoding every step", |vm| {
+            vm.decode_cache = false;
+            #[cfg(feature = "jit")]
+            {
+                vm.jit = false;
+            }
+        }),
+        ("pre-decoded", |_vm| {
+            #[cfg(feature = "jit")]
+            {
+                _vm.jit = false;
+            }
+        }),
+    ];
+    #[cfg(feature = "jit")]
+    modes.push(("jit", |vm| vm.jit = true));
+    modes
+}
+
+/// runs `src` for `steps` instructions and returns how long it took
+pub fn time(src: &str, steps: u64, setup: Setup) -> Duration {
+    let mut asm = Assembler::new();
+    let prog = asm
+        .assemble(src)
+        .expect("benchmark program doesn't assemble");
+    let mut vm = VM::new();
+    vm.load(prog, asm.ro)
+        .expect("benchmark program doesn't load");
+    setup(&mut vm);
+    vm.fuel = Some(steps);
+    let start = Instant::now();
+    let exit = vm.run();
+    let elapsed = start.elapsed();
+    assert_eq!(exit, VMExit::OutOfFuel, "benchmark program stopped early");
+    elapsed
+}
+
+/// prints how fast each program runs in each mode, and the speedup over the first
+pub fn run(steps: u64) {
+    println!("{} instructions per program", steps);
+    for (name, src) in PROGRAMS {
+        println!("{}", name);
+        let mut base = None;
+        for (mode, setup) in modes() {
+            let secs = time(src, steps, setup).as_secs_f64();
+            let base = *base.get_or_insert(secs);
+            println!(
+                "\t{:<20} {:>8.1} Minstr/s {:>6.2}x",
+                mode,
+                steps as f64 / secs / 1e6,
+                base / secs
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_programs_run() {
+        for (_, src) in PROGRAMS {
+            for (_, setup) in modes() {
+                time(src, 1000, setup);
+            }
+        }
+    }
+}
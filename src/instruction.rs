@@ -14,8 +14,8 @@ pub enum Opcode {
     INC,
     DEC,
     JMP,
-    JMPB,
-    JMPF,
+    JMPB, // jumps back by the value of a register, from the start of the instruction
+    JMPF, // jumps forward by the value of a register, from the start of the instruction
     EQ,
     NEQ,
     GT,
@@ -30,6 +30,50 @@ pub enum Opcode {
     ALOC,
     PRTS, // print string
     IGL,
+    // appended so older bytecode keeps its encoding
+    FREE,
+    SYSCALL,
+    LOADF,
+    ADDF,
+    SUBF,
+    MULF,
+    DIVF,
+    EQF,
+    NEQF,
+    GTF,
+    LTF,
+    GEQF,
+    LEQF,
+    ITOF,
+    FTOI,
+    XOR,
+    SHL,
+    SHR,
+    SAR,
+    ROL,
+    ROR,
+    MOD,
+    ADDI,
+    SUBI,
+    MULI,
+    EQI,
+    NEQI,
+    GTI,
+    LTI,
+    GEQI,
+    LEQI,
+    JZ,
+    JNZ,
+    JS,
+    JNS,
+    JC,
+    JNC,
+    JO,
+    JNO,
+    // branches to the start of this instruction plus a signed 16 bit offset
+    BR,
+    BEQ,
+    BNE,
 }
 
 #[derive(Debug, PartialEq)]
@@ -66,7 +110,49 @@ impl From<&str> for Opcode {
             "jeq" => Opcode::JEQ,
             "jne" => Opcode::JNE,
             "aloc" => Opcode::ALOC,
+            "free" => Opcode::FREE,
             "prts" => Opcode::PRTS,
+            "syscall" => Opcode::SYSCALL,
+            "loadf" => Opcode::LOADF,
+            "addf" => Opcode::ADDF,
+            "subf" => Opcode::SUBF,
+            "mulf" => Opcode::MULF,
+            "divf" => Opcode::DIVF,
+            "eqf" => Opcode::EQF,
+            "neqf" => Opcode::NEQF,
+            "gtf" => Opcode::GTF,
+            "ltf" => Opcode::LTF,
+            "geqf" => Opcode::GEQF,
+            "leqf" => Opcode::LEQF,
+            "itof" => Opcode::ITOF,
+            "ftoi" => Opcode::FTOI,
+            "xor" => Opcode::XOR,
+            "shl" => Opcode::SHL,
+            "shr" => Opcode::SHR,
+            "sar" => Opcode::SAR,
+            "rol" => Opcode::ROL,
+            "ror" => Opcode::ROR,
+            "mod" => Opcode::MOD,
+            "addi" => Opcode::ADDI,
+            "subi" => Opcode::SUBI,
+            "muli" => Opcode::MULI,
+            "eqi" => Opcode::EQI,
+            "neqi" => Opcode::NEQI,
+            "gti" => Opcode::GTI,
+            "lti" => Opcode::LTI,
+            "geqi" => Opcode::GEQI,
+            "leqi" => Opcode::LEQI,
+            "jz" => Opcode::JZ,
+            "jnz" => Opcode::JNZ,
+            "js" => Opcode::JS,
+            "jns" => Opcode::JNS,
+            "jc" => Opcode::JC,
+            "jnc" => Opcode::JNC,
+            "jo" => Opcode::JO,
+            "jno" => Opcode::JNO,
+            "br" => Opcode::BR,
+            "beq" => Opcode::BEQ,
+            "bne" => Opcode::BNE,
             _ => Opcode::IGL,
         }
     }
@@ -74,7 +160,7 @@ impl From<&str> for Opcode {
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode: opcode }
+        Instruction { opcode }
     }
 }
 
@@ -107,12 +193,95 @@ impl From<u8> for Opcode {
             x if x == Opcode::JEQ as u8 => Opcode::JEQ,
             x if x == Opcode::JNE as u8 => Opcode::JNE,
             x if x == Opcode::ALOC as u8 => Opcode::ALOC,
+            x if x == Opcode::FREE as u8 => Opcode::FREE,
             x if x == Opcode::PRTS as u8 => Opcode::PRTS,
+            x if x == Opcode::SYSCALL as u8 => Opcode::SYSCALL,
+            x if x == Opcode::LOADF as u8 => Opcode::LOADF,
+            x if x == Opcode::ADDF as u8 => Opcode::ADDF,
+            x if x == Opcode::SUBF as u8 => Opcode::SUBF,
+            x if x == Opcode::MULF as u8 => Opcode::MULF,
+            x if x == Opcode::DIVF as u8 => Opcode::DIVF,
+            x if x == Opcode::EQF as u8 => Opcode::EQF,
+            x if x == Opcode::NEQF as u8 => Opcode::NEQF,
+            x if x == Opcode::GTF as u8 => Opcode::GTF,
+            x if x == Opcode::LTF as u8 => Opcode::LTF,
+            x if x == Opcode::GEQF as u8 => Opcode::GEQF,
+            x if x == Opcode::LEQF as u8 => Opcode::LEQF,
+            x if x == Opcode::ITOF as u8 => Opcode::ITOF,
+            x if x == Opcode::FTOI as u8 => Opcode::FTOI,
+            x if x == Opcode::XOR as u8 => Opcode::XOR,
+            x if x == Opcode::SHL as u8 => Opcode::SHL,
+            x if x == Opcode::SHR as u8 => Opcode::SHR,
+            x if x == Opcode::SAR as u8 => Opcode::SAR,
+            x if x == Opcode::ROL as u8 => Opcode::ROL,
+            x if x == Opcode::ROR as u8 => Opcode::ROR,
+            x if x == Opcode::MOD as u8 => Opcode::MOD,
+            x if x == Opcode::ADDI as u8 => Opcode::ADDI,
+            x if x == Opcode::SUBI as u8 => Opcode::SUBI,
+            x if x == Opcode::MULI as u8 => Opcode::MULI,
+            x if x == Opcode::EQI as u8 => Opcode::EQI,
+            x if x == Opcode::NEQI as u8 => Opcode::NEQI,
+            x if x == Opcode::GTI as u8 => Opcode::GTI,
+            x if x == Opcode::LTI as u8 => Opcode::LTI,
+            x if x == Opcode::GEQI as u8 => Opcode::GEQI,
+            x if x == Opcode::LEQI as u8 => Opcode::LEQI,
+            x if x == Opcode::JZ as u8 => Opcode::JZ,
+            x if x == Opcode::JNZ as u8 => Opcode::JNZ,
+            x if x == Opcode::JS as u8 => Opcode::JS,
+            x if x == Opcode::JNS as u8 => Opcode::JNS,
+            x if x == Opcode::JC as u8 => Opcode::JC,
+            x if x == Opcode::JNC as u8 => Opcode::JNC,
+            x if x == Opcode::JO as u8 => Opcode::JO,
+            x if x == Opcode::JNO as u8 => Opcode::JNO,
+            x if x == Opcode::BR as u8 => Opcode::BR,
+            x if x == Opcode::BEQ as u8 => Opcode::BEQ,
+            x if x == Opcode::BNE as u8 => Opcode::BNE,
             _ => Opcode::IGL,
         }
     }
 }
 
+impl Opcode {
+    /// whether the operand is an offset from the instruction, filled from a label by the assembler
+    pub fn is_relative_branch(self) -> bool {
+        matches!(self, Opcode::BR | Opcode::BEQ | Opcode::BNE)
+    }
+
+    /// whether the instruction jumps only on some condition
+    pub fn is_conditional_jump(self) -> bool {
+        use Opcode::*;
+        matches!(
+            self,
+            JEQ | JNE | BEQ | BNE | JZ | JNZ | JS | JNS | JC | JNC | JO | JNO
+        )
+    }
+
+    /// the variant taking a 16 bit immediate instead of a second register
+    pub fn immediate(self) -> Option<Opcode> {
+        match self {
+            Opcode::ADD => Some(Opcode::ADDI),
+            Opcode::SUB => Some(Opcode::SUBI),
+            Opcode::MUL => Some(Opcode::MULI),
+            Opcode::EQ => Some(Opcode::EQI),
+            Opcode::NEQ => Some(Opcode::NEQI),
+            Opcode::GT => Some(Opcode::GTI),
+            Opcode::LT => Some(Opcode::LTI),
+            Opcode::GEQ => Some(Opcode::GEQI),
+            Opcode::LEQ => Some(Opcode::LEQI),
+            _ => None,
+        }
+    }
+
+    /// whether the instruction takes a 16 bit signed immediate
+    pub fn is_immediate(self) -> bool {
+        use Opcode::*;
+        matches!(
+            self,
+            ADDI | SUBI | MULI | EQI | NEQI | GTI | LTI | GEQI | LEQI
+        )
+    }
+}
+
 impl fmt::Display for Opcode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -128,6 +297,23 @@ mod tests {
         assert_eq!(opcode, Opcode::HLT);
     }
     #[test]
+    fn test_encoding() {
+        // the numbers of the first opcodes are fixed by older bytecode
+        assert_eq!(Opcode::from(25), Opcode::ALOC);
+        assert_eq!(Opcode::from(26), Opcode::PRTS);
+        assert_eq!(Opcode::IGL as u8, 27);
+        assert_eq!(Opcode::from(28), Opcode::FREE);
+        assert_eq!(Opcode::from(255), Opcode::IGL);
+    }
+    #[test]
+    fn test_immediate() {
+        assert_eq!(Opcode::ADD.immediate(), Some(Opcode::ADDI));
+        assert_eq!(Opcode::GEQ.immediate(), Some(Opcode::GEQI));
+        assert_eq!(Opcode::DIV.immediate(), None);
+        assert!(Opcode::ADD.immediate().unwrap().is_immediate());
+        assert!(!Opcode::ADD.is_immediate());
+    }
+    #[test]
     fn test_create_instruction() {
         let i = Instruction::new(Opcode::HLT);
         assert_eq!(i.opcode, Opcode::HLT);
@@ -1,5 +1,6 @@
 #[macro_use]
 pub mod asm;
+pub mod bench;
 pub mod instruction;
 pub mod repl;
 pub mod sched;
@@ -8,23 +9,175 @@ pub mod vm;
 extern crate clap;
 extern crate nom;
 
-use clap::{load_yaml, App};
+use clap::{load_yaml, App, ArgMatches};
+
+use crate::asm::{Assembler, PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
+use crate::repl::Flow;
+use crate::vm::coverage::Coverage;
+use crate::vm::profile::Profile;
+use crate::vm::trace::{parse_range, TraceFormat, Tracer};
+use crate::vm::{VMExit, VM};
 
 fn main() {
-    let mut repl = repl::REPL::new();
     let yaml = load_yaml!("cli.yaml");
     let matches = App::from_yaml(yaml).get_matches();
-    let target = matches.value_of("INPUT_FILE");
-    match target {
-        Some(filename) => match std::fs::read(filename) {
-            Ok(bytes) => {
-                repl.run(Some(bytes));
-            }
-            _ => {
-                println!("Can't read file: {}", filename);
+    if let Some(matches) = matches.subcommand_matches("bench") {
+        let steps = matches.value_of("STEPS").unwrap_or("10000000");
+        match steps.parse() {
+            Ok(steps) => bench::run(steps),
+            Err(_) => {
+                println!("Invalid number of steps: {}", steps);
                 std::process::exit(1);
             }
-        },
-        None => repl.run(None),
+        }
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("run") {
+        std::process::exit(run(matches));
+    }
+    if let Some(matches) = matches.subcommand_matches("repl") {
+        std::process::exit(repl(matches));
+    }
+    std::process::exit(repl(&matches));
+}
+
+/// runs the REPL: the script, then commands at the prompt or with `--batch`
+/// from stdin, returning the exit code
+fn repl(matches: &ArgMatches) -> i32 {
+    let mut repl = repl::REPL::new();
+    if let Some(filename) = matches.value_of("INPUT_FILE") {
+        match std::fs::read(filename) {
+            Ok(bytes) => repl.load(filename, &bytes),
+            Err(e) => {
+                println!("Can't read file {}: {}", filename, e);
+                return 1;
+            }
+        }
+    }
+    repl.handle_interrupts();
+    let batch = matches.is_present("BATCH");
+    let flow = match matches.value_of("SCRIPT") {
+        Some(script) => repl.source(script),
+        None if batch => repl.batch(std::io::stdin().lock()),
+        None => Flow::Continue,
+    };
+    if !batch && flow == Flow::Continue {
+        repl.run();
+    }
+    if batch && repl.failures > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// assembles and runs a program file, returning the exit code
+fn run(matches: &ArgMatches) -> i32 {
+    let filename = matches.value_of("INPUT_FILE").unwrap();
+    let bytes = match std::fs::read(filename) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("Can't read file {}: {}", filename, e);
+            return 1;
+        }
+    };
+    if bytes.len() < PIE_HEADER_LENGTH || bytes[..PIE_HEADER_PREFIX.len()] != PIE_HEADER_PREFIX {
+        println!("Wrong file or missing magic bytes");
+        return 1;
+    }
+    let mut asm = Assembler::new();
+    asm.debug = !matches.is_present("STRIP");
+    asm.file = filename.to_string();
+    let source = String::from_utf8_lossy(&bytes[PIE_HEADER_LENGTH..]);
+    let program = match asm.assemble(&source) {
+        Ok(program) => program,
+        Err(e) => {
+            println!("Cannot parse file, {:#?}", e);
+            return 1;
+        }
+    };
+    let mut vm = VM::new();
+    if let Err(e) = vm.load(program, asm.ro.clone()) {
+        println!("Cannot load program, {:?}", e);
+        return 1;
+    }
+    if matches.is_present("TRACE") {
+        match tracer(matches) {
+            Ok(tracer) => vm.tracer = Some(tracer),
+            Err(e) => {
+                println!("{}", e);
+                return 1;
+            }
+        }
+    }
+    if matches.is_present("PROFILE") {
+        vm.profile = Some(Profile::default());
+    }
+    if matches.is_present("COVERAGE") {
+        vm.coverage = Some(Coverage::default());
+    }
+    let exit = vm.run();
+    if let Some(profile) = &vm.profile {
+        println!(
+            "{}",
+            profile.report(vm.program(), &asm.symbols, vm.debug_info.as_ref(), 20)
+        );
+        if let Some(file) = matches.value_of("FOLDED") {
+            let written = std::fs::File::create(file).and_then(|mut f| {
+                profile.write_folded(&mut f, vm.program(), &asm.symbols, vm.debug_info.as_ref())
+            });
+            if let Err(e) = written {
+                println!("Can't write {}: {}", file, e);
+            }
+        }
+    }
+    if let Some(coverage) = &vm.coverage {
+        match vm.debug_info {
+            Some(_) => print!("{}", coverage.annotate(&source, &asm.lines, vm.program())),
+            None => print!("{}", coverage.annotate_pcs(vm.program())),
+        }
+        if let Some(file) = matches.value_of("LCOV") {
+            let written = std::fs::File::create(file)
+                .and_then(|mut f| coverage.write_lcov(&mut f, filename, &asm.lines, vm.program()));
+            if let Err(e) = written {
+                println!("Can't write {}: {}", file, e);
+            }
+        }
+    }
+    match exit {
+        VMExit::Halted => 0,
+        VMExit::Faulted => {
+            if let Some(f) = &vm.fault {
+                println!("Faulted at {}: {}", vm.describe_pc(f.pc()), f);
+            }
+            1
+        }
+        exit => {
+            println!("Stopped: {:?}", exit);
+            1
+        }
+    }
+}
+
+/// the tracer `--trace`, `--trace-format` and `--trace-range` ask for
+fn tracer(matches: &ArgMatches) -> Result<Tracer, String> {
+    let format = matches
+        .value_of("TRACE_FORMAT")
+        .map_or(Ok(TraceFormat::Text), str::parse)?;
+    let mut tracer = match matches.value_of("TRACE") {
+        Some(file) => {
+            let f =
+                std::fs::File::create(file).map_err(|e| format!("Can't create {}: {}", file, e))?;
+            Tracer::new(format, std::io::BufWriter::new(f))
+        }
+        None => Tracer::stdout(format),
+    };
+    if let Some(range) = matches.value_of("TRACE_RANGE") {
+        let addr = |s: &str| match s.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        };
+        tracer.range = Some(parse_range(range, addr).ok_or(format!("Invalid range {}", range))?);
     }
+    Ok(tracer)
 }
@@ -0,0 +1,98 @@
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use super::COMMANDS;
+use crate::instruction::Opcode;
+
+/// Tab completion of REPL commands, opcode mnemonics and `@labels`
+#[derive(Default)]
+pub struct ReplHelper {
+    pub labels: Vec<String>, // of the loaded program, kept current by the REPL
+}
+
+impl ReplHelper {
+    /// what `word` could become; `first` if it starts the input, after at
+    /// most a label declaration
+    pub fn candidates(&self, word: &str, first: bool) -> Vec<String> {
+        let mut words: Vec<String> = if let Some(label) = word.strip_prefix('@') {
+            self.labels
+                .iter()
+                .filter(|l| l.starts_with(label))
+                .map(|l| format!("@{}", l))
+                .collect()
+        } else if first && word.starts_with('.') {
+            COMMANDS
+                .iter()
+                .filter_map(|c| c.split_whitespace().next())
+                .map(|c| c.trim_end_matches(','))
+                .filter(|c| c.starts_with('.'))
+                .chain([".data", ".code"])
+                .filter(|c| c.starts_with(word))
+                .map(str::to_string)
+                .collect()
+        } else if first {
+            (0..=u8::MAX)
+                .map(Opcode::from)
+                .filter(|&op| op != Opcode::IGL)
+                .map(|op| op.to_string().to_lowercase())
+                .filter(|op| op.starts_with(word))
+                .collect()
+        } else {
+            vec![]
+        };
+        words.sort();
+        words.dedup();
+        words
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+        let first = match before[..] {
+            [] => true,
+            [label] => label.ends_with(':'),
+            _ => false,
+        };
+        Ok((start, self.candidates(&line[start..pos], first)))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_candidates() {
+        let helper = ReplHelper {
+            labels: vec!["top".to_string(), "total".to_string(), "end".to_string()],
+        };
+        assert_eq!(helper.candidates(".reg", true), vec![".registers"]);
+        assert_eq!(helper.candidates(".a", true), vec![".asm"]);
+        assert_eq!(helper.candidates(".d", true), vec![".data", ".delete"]);
+        assert_eq!(helper.candidates("hl", true), vec!["hlt"]);
+        assert!(helper.candidates("j", true).contains(&"jmp".to_string()));
+        assert_eq!(helper.candidates("@to", false), vec!["@top", "@total"]);
+        assert!(helper.candidates("hl", false).is_empty());
+    }
+}
@@ -1,102 +1,304 @@
-use crate::asm::parser_program::*;
+pub mod completer;
+
+use crate::asm::parser_program::program;
 use crate::asm::Assembler;
+use crate::asm::PieHeader;
+use crate::asm::SymbolType;
 use crate::asm::PIE_HEADER_LENGTH;
 use crate::asm::PIE_HEADER_PREFIX;
 use crate::instruction::Opcode;
 use crate::sched::Scheduler;
 use crate::vm;
+use crate::vm::debug::{Memory, Watch};
+use crate::vm::snapshot::{SnapshotError, SNAPSHOT_VERSION};
+use crate::vm::trace::{parse_range, TraceFormat, Tracer};
+use crate::vm::VMEventType;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Config, Editor};
 use std;
 use std::io;
-use std::io::Write;
 use std::num::ParseIntError;
-use std::thread::JoinHandle;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use self::completer::ReplHelper;
+
+/// steps `.record` keeps when not told how many
+const DEFAULT_RECORDING: usize = 100_000;
+/// bytes `.mem` shows when not told how many
+const DEFAULT_DUMP: usize = 64;
+/// lines of history kept in the history file
+const HISTORY_SIZE: usize = 1000;
+/// how deep `.source` may nest, so a file sourcing itself stops
+const MAX_SOURCE_DEPTH: usize = 16;
+
+/// How `.registers` shows integers
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Radix {
+    Signed,
+    Decimal, // unsigned
+    Hex,
+}
+
+impl Radix {
+    fn format(self, v: i32) -> String {
+        match self {
+            Radix::Signed => v.to_string(),
+            Radix::Decimal => (v as u32).to_string(),
+            Radix::Hex => format!("{:#010x}", v as u32),
+        }
+    }
+}
+
+/// what `.help` lists, also used for completion
+pub const COMMANDS: &[&str] = &[
+    ".help",
+    ".quit",
+    ".registers [signed|dec|hex]",
+    ".history",
+    ".program",
+    ".instruct BYTES",
+    "INSTRUCTION, e.g. load $0 #1 or top: inc $0",
+    ".asm, then lines of assembly and a blank line",
+    ".eval on|off",
+    ".run",
+    ".continue",
+    ".step",
+    ".break [ADDR|@label]",
+    ".watch $REG|heap[ADDR]",
+    ".delete N",
+    ".record [N|off]",
+    ".reverse_step",
+    ".reverse_continue",
+    ".last_write $REG|heap[ADDR]",
+    ".trace on [text|json] [FILE] [START..END]",
+    ".trace off",
+    ".clear_program",
+    ".ro_data",
+    ".mem heap|ro ADDR [LEN]",
+    ".strings",
+    ".poke heap|ro ADDR BYTES",
+    ".load_file FILE",
+    ".source FILE",
+    ".save_vm FILE",
+    ".load_vm FILE",
+    ".events [FILE]",
+];
+
+/// Whether the REPL goes on after a line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
 
 pub struct REPL {
     cmd: Vec<String>,
     vm: vm::VM,
     asm: Assembler,
     sched: Scheduler,
+    source: String,             // assembly of the program, loaded or entered
+    assembled: Vec<u8>,         // the program as last assembled from `source`
+    block: Option<Vec<String>>, // lines of an unfinished `.asm` block
+    eval: bool,                 // run entered instructions right away
+    pub failures: usize,        // commands that failed, for --batch
+    depth: usize,               // files being sourced
+    running: Arc<AtomicBool>,   // the VM is running, for the Ctrl-C handler
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl REPL {
     pub fn new() -> Self {
+        let mut asm = Assembler::new();
+        asm.debug = true;
         REPL {
             vm: vm::VM::new(),
             cmd: vec![],
-            asm: Assembler::new(),
+            asm,
             sched: Scheduler::new(),
+            source: String::new(),
+            assembled: vec![],
+            block: None,
+            eval: false,
+            failures: 0,
+            depth: 0,
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// loads the contents of a program file
+    pub fn load(&mut self, filename: &str, bytes: &[u8]) {
+        self.asm.file = filename.to_string();
+        self.load_bytes(bytes);
+    }
+
+    /// Ctrl-C interrupts the VM during .run and the like instead of the REPL,
+    /// and exits otherwise; at the prompt it's handled by the line editor
+    pub fn handle_interrupts(&self) {
+        let (interrupt, running) = (self.vm.interrupt.clone(), self.running.clone());
+        let handler = move || match running.load(Ordering::Relaxed) {
+            true => interrupt.store(true, Ordering::Relaxed),
+            // reading a script or piped commands
+            false => std::process::exit(130),
+        };
+        if let Err(e) = ctrlc::set_handler(handler) {
+            println!("Ctrl-C won't interrupt programs, {}", e);
         }
     }
 
-    pub fn run(&mut self, prog_bytes: Option<Vec<u8>>) {
+    /// reads commands at a prompt until `.quit` or end of input
+    pub fn run(&mut self) {
         println!("REPL version 0.1");
-        if let Some(bytes) = prog_bytes {
-            if !self.verify_header(&bytes) {
-                println!("Wrong file or missing magic bytes");
-            } else {
-                let bytes = &bytes[PIE_HEADER_LENGTH..];
-                match self.asm.assemble(std::str::from_utf8(&bytes).unwrap()) {
-                    Ok(mut prog) => {
-                        self.vm.program.append(&mut prog);
-                        self.vm.pc = PIE_HEADER_LENGTH as usize;
-                        self.vm.ro_data = self.asm.ro.clone();
-                        println!("Parsed.");
+        let mut editor = match Self::editor() {
+            Ok(editor) => editor,
+            Err(e) => {
+                println!("Cannot start the line editor, {}", e);
+                return;
+            }
+        };
+        if let Some(file) = history_file() {
+            // there's none the first time
+            let _ = editor.load_history(&file);
+        }
+        let mut history: Vec<String> = editor.history().iter().cloned().collect();
+        history.append(&mut self.cmd);
+        self.cmd = history;
+
+        loop {
+            if let Some(helper) = editor.helper_mut() {
+                helper.labels = self.asm.symbols.names().map(str::to_string).collect();
+            }
+            let prompt = if self.block.is_some() { "... " } else { "> " };
+            let input = match editor.readline(prompt) {
+                Ok(input) => input,
+                Err(ReadlineError::Interrupted) => {
+                    if self.block.take().is_some() {
+                        println!("Dropped the .asm block");
                     }
-                    Err(e) => {
-                        println!("Cannot parse file, {:#?}", e);
+                    continue;
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    println!("Cannot read input, {}", e);
+                    break;
+                }
+            };
+            let input = input.trim();
+            if !input.is_empty() {
+                let _ = editor.add_history_entry(input);
+            }
+            if self.execute(input) == Flow::Quit {
+                break;
+            }
+        }
+        Self::save_history(&mut editor);
+    }
+
+    /// runs commands from `input` without prompts until `.quit` or the end
+    pub fn batch(&mut self, input: impl io::BufRead) -> Flow {
+        for line in input.lines() {
+            match line {
+                Ok(line) => {
+                    if self.execute(line.trim()) == Flow::Quit {
+                        return Flow::Quit;
                     }
                 }
+                Err(e) => {
+                    self.error(format!("Cannot read input, {}", e));
+                    break;
+                }
             }
         }
+        // a trailing `.asm` block needs no blank line to end it
+        self.execute("")
+    }
 
-        let mut thread_vm: Option<JoinHandle<vm::VM>> = None;
-        loop {
-            let mut input = String::new();
-            let stdin = io::stdin();
-            print!("> ");
-            io::stdout().flush().expect("flush");
-            stdin.read_line(&mut input).expect("cannot read line");
-            let input = input.trim();
-            let buf: Vec<&str> = input.split(" ").collect();
-            let cmd = buf.first();
-            if let None = cmd {
-                continue;
-            }
-            let (cmd, args) = buf.split_at(1);
-            match cmd.join("").as_str() {
-                "" => continue,
-                ".help" => {
-                    println!("Commands:");
-                    for cmd in vec![
-                        ".help",
-                        ".quit",
-                        ".registers",
-                        ".history",
-                        ".program",
-                        ".instruct BYTES",
-                        ".run",
-                        ".step",
-                        ".clear_program",
-                        ".ro_data",
-                        ".load_file FILE",
-                    ] {
-                        println!("\t{}", cmd);
-                    }
-                }
-                ".quit" => std::process::exit(0),
-                ".history" => {
-                    for (i, cmd) in self.cmd.iter().enumerate() {
-                        println!("{}\t{}", i + 1, cmd)
-                    }
-                }
-                ".program" => {
-                    println!("Loaded program:");
-                    for (idx, i) in self.vm.program[PIE_HEADER_LENGTH..].iter().enumerate() {
+    /// runs the commands in a file, skipping `#` comments
+    pub fn source(&mut self, path: &str) -> Flow {
+        if self.depth >= MAX_SOURCE_DEPTH {
+            self.error(format!("Not sourcing {}, files nest too deep", path));
+            return Flow::Continue;
+        }
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                self.error(format!("Error reading the file: {}", e));
+                return Flow::Continue;
+            }
+        };
+        // blank lines stay, they end `.asm` blocks
+        let lines: Vec<&str> = text
+            .lines()
+            .filter(|l| !l.trim_start().starts_with('#'))
+            .collect();
+        self.depth += 1;
+        let flow = self.batch(io::Cursor::new(lines.join("\n")));
+        self.depth -= 1;
+        flow
+    }
+
+    /// runs one line of input: a command, assembly or a line of an `.asm` block
+    pub fn execute(&mut self, input: &str) -> Flow {
+        if let Some(block) = self.block.as_mut() {
+            // a blank line ends the block
+            if input.is_empty() {
+                let block = self.block.take().unwrap();
+                self.add_source(&block.join("\n"));
+            } else {
+                block.push(input.to_string());
+                self.cmd.push(input.to_string());
+            }
+            return Flow::Continue;
+        }
+        if input.is_empty() {
+            return Flow::Continue;
+        }
+        self.cmd.push(input.to_string());
+        let buf: Vec<&str> = input.split(" ").collect();
+        let (cmd, args) = buf.split_at(1);
+        match cmd.join("").as_str() {
+            ".help" => {
+                println!("Commands:");
+                for cmd in COMMANDS {
+                    println!("\t{}", cmd);
+                }
+            }
+            ".quit" => return Flow::Quit,
+            ".history" => {
+                for (i, cmd) in self.cmd.iter().enumerate() {
+                    println!("{}\t{}", i + 1, cmd)
+                }
+            }
+            ".program" => {
+                println!("Loaded program:");
+                let start = PieHeader::parse(self.vm.program())
+                    .map_or(PIE_HEADER_LENGTH, |h| h.code_offset);
+                if let Some(debug) = &self.vm.debug_info {
+                    for pc in (start..self.vm.program().len()).step_by(4) {
+                        println!(
+                            "{:#06x}  {:<12} {:<24} {}",
+                            pc,
+                            debug
+                                .label_at(pc)
+                                .map_or(String::new(), |l| l.to_string() + ":"),
+                            self.vm.disassemble(pc),
+                            debug.location(pc).unwrap_or_default()
+                        );
+                    }
+                } else {
+                    for (idx, i) in self.vm.program()[start..].iter().enumerate() {
                         if (idx % 4) == 0 {
                             let op: Opcode = (*i).into();
                             match op {
                                 Opcode::IGL => print!("{:x} ", i),
-                                _ => print!("{} ", op.to_string()),
+                                _ => print!("{} ", op),
                             }
                         } else {
                             print!("{:x} ", i);
@@ -106,76 +308,540 @@ impl REPL {
                         }
                     }
                 }
-                ".registers" => {
-                    println!("Registers:");
-                    match thread_vm.take() {
-                        Some(vm_handle) => {
-                            let vm = vm_handle.join();
-                            let vm = vm.unwrap();
-                            for (i, reg) in vm.regs.iter().enumerate() {
-                                print!("reg{:02}: {}\t", i, reg);
-                                if i > 0 && (i % 4) == 3 {
-                                    println!()
-                                }
-                            }
-                            println!();
-                            println!("remainder:{}\nflag:{}", vm.remainder, vm.bool_flag);
-                            println!("pc:{}", vm.pc);
-                        }
-                        None => todo!(),
+            }
+            ".registers" => match args.first().copied() {
+                None | Some("signed") => print!("{}", self.registers(Radix::Signed)),
+                Some("dec") => print!("{}", self.registers(Radix::Decimal)),
+                Some("hex") => print!("{}", self.registers(Radix::Hex)),
+                Some(_) => self.error("Try .registers [signed|dec|hex]".to_string()),
+            },
+            ".instruct" => match self.parse_hex(&args.join(" ")) {
+                Ok(mut bytes) => {
+                    self.vm.program_mut().append(&mut bytes);
+                }
+                Err(e) => self.error(format!("Unable to parse hex, {:?}", e)),
+            },
+            ".step" => {
+                let events = self.vm.events().len();
+                if self.vm.step() {
+                    self.report_pause(events);
+                }
+            }
+            ".run" => {
+                // from the start of the program
+                self.vm.pc = PieHeader::parse(self.vm.program()).map_or(0, |h| h.code_offset);
+                self.run_vm();
+            }
+            ".continue" => self.run_vm(),
+            ".break" => match args.first() {
+                Some(addr) => match self.parse_addr(addr) {
+                    Some(pc) => {
+                        let n = self.vm.add_breakpoint(pc);
+                        println!("Breakpoint {} at {:#x}", n, pc);
+                    }
+                    None => self.error(format!("Invalid address {}", addr)),
+                },
+                None => {
+                    for (n, point) in &self.vm.debugger.points {
+                        println!("{}\t{}", n, point);
                     }
                 }
-                ".instruct" => match self.parse_hex(&args.join(" ")) {
-                    Ok(mut bytes) => self.vm.program.append(&mut bytes),
-                    Err(e) => println!("Unable to parse hex, {:?}", e),
+            },
+            ".watch" => match args.first().and_then(|w| self.parse_watch(w)) {
+                Some(w) => {
+                    let n = self.vm.add_watchpoint(w);
+                    println!("Watchpoint {} on {}", n, w);
+                }
+                None => self.error("Watch a register ($N) or a heap byte (heap[ADDR])".to_string()),
+            },
+            ".delete" => match args.first().and_then(|n| n.parse().ok()) {
+                Some(n) if self.vm.debugger.delete(n) => println!("Deleted {}", n),
+                _ => self.error(format!("No breakpoint or watchpoint {}", args.join(" "))),
+            },
+            ".record" => match args.first().copied() {
+                Some("off") => {
+                    self.vm.stop_recording();
+                    println!("Stopped recording");
+                }
+                n => match n.map_or(Ok(DEFAULT_RECORDING), str::parse) {
+                    Ok(n) => {
+                        self.vm.record(n);
+                        println!("Recording the last {} steps", n);
+                    }
+                    Err(_) => self.error(format!("Invalid number of steps {}", args.join(" "))),
                 },
-                ".step" => {
-                    self.vm.step();
-                    ()
-                }
-                ".run" => {
-                    thread_vm = Some(self.sched.get_thread(self.vm.clone()));
-                    ()
-                }
-                ".ro_data" => println!("Read-Only data: {:?}", self.vm.ro_data),
-                ".clear_program" => self.vm.program.clear(),
-                ".load_file" => {
-                    if args.len() == 0 {
-                        println!("No filename specified");
-                        continue;
-                    }
-                    println!("Loading {}", args[0]);
-                    match std::fs::read(args[0]) {
-                        Ok(data) => {
-                            if !self.verify_header(&data) {
-                                println!("Wrong file or missing magic bytes");
-                                continue;
-                            }
-                            let data = &data[PIE_HEADER_LENGTH..];
-                            match self.asm.assemble(std::str::from_utf8(&data).unwrap()) {
-                                Ok(mut prog) => {
-                                    self.vm.program.append(&mut prog);
-                                    println!("Parsed.");
-                                }
-                                Err(e) => {
-                                    println!("Cannot parse file, {:#?}", e);
-                                }
-                            }
+            },
+            ".reverse_step" => {
+                if self.vm.reverse_step() {
+                    println!("Back at {:#x}", self.vm.pc);
+                } else {
+                    self.error("Nothing recorded to go back to".to_string());
+                }
+            }
+            ".reverse_continue" => {
+                if self.vm.reverse_continue() {
+                    println!("Back at breakpoint, pc {:#x}", self.vm.pc);
+                } else {
+                    println!("Back at the start of the recording, pc {:#x}", self.vm.pc);
+                }
+            }
+            ".last_write" => match (
+                args.first().and_then(|w| self.parse_watch(w)),
+                &self.vm.history,
+            ) {
+                (None, _) => {
+                    self.error("Ask about a register ($N) or a heap byte (heap[ADDR])".to_string())
+                }
+                (_, None) => self.error("Not recording, try .record".to_string()),
+                (Some(w), Some(history)) => match history.last_write(w) {
+                    Some(d) => {
+                        println!("{} was last written at step {}, pc {:#x}", w, d.step, d.pc)
+                    }
+                    None => println!(
+                        "{} wasn't written in the last {} steps",
+                        w,
+                        history.deltas().len()
+                    ),
+                },
+            },
+            ".trace" => match args.split_first() {
+                Some((&"on", opts)) => match self.tracer(opts) {
+                    Ok(tracer) => {
+                        self.vm.tracer = Some(tracer);
+                        println!("Tracing");
+                    }
+                    Err(e) => self.error(e.to_string()),
+                },
+                Some((&"off", _)) => {
+                    if let Some(tracer) = self.vm.tracer.take() {
+                        let _ = tracer.flush();
+                    }
+                    println!("Stopped tracing");
+                }
+                _ => self.error(
+                    "Try .trace on [text|json] [FILE] [START..END] or .trace off".to_string(),
+                ),
+            },
+            ".asm" => {
+                println!("Enter assembly, end with a blank line");
+                self.block = Some(vec![]);
+            }
+            ".eval" => match args.first().copied() {
+                Some("on") => {
+                    self.eval = true;
+                    println!("Running instructions as they are entered");
+                }
+                Some("off") => self.eval = false,
+                _ => self.error("Try .eval on or .eval off".to_string()),
+            },
+            ".ro_data" => print!("{}", hexdump(&self.vm.ro_data, 0)),
+            ".mem" => match (args.first().and_then(|m| memory(m)), args.get(1)) {
+                (Some(m), Some(addr)) => {
+                    let len = match args.get(2) {
+                        Some(len) => self.parse_addr(len),
+                        None => Some(DEFAULT_DUMP),
+                    };
+                    let bytes = self.vm.memory(m);
+                    match (self.parse_addr(addr), len) {
+                        (Some(addr), Some(len)) if addr < bytes.len() => {
+                            let end = addr.saturating_add(len).min(bytes.len());
+                            print!("{}", hexdump(&bytes[addr..end], addr))
                         }
-                        Err(e) => {
-                            println!("Error reading the file: {}", e);
+                        (Some(_), Some(_)) => {
+                            self.error(format!("There are {} bytes", bytes.len()))
                         }
+                        _ => self.error("Try .mem heap|ro ADDR [LEN]".to_string()),
+                    }
+                }
+                _ => self.error("Try .mem heap|ro ADDR [LEN]".to_string()),
+            },
+            ".strings" => {
+                let labels: Vec<(&str, usize)> = self
+                    .vm
+                    .debug_info
+                    .iter()
+                    .flat_map(|d| &d.symbols)
+                    .filter(|(_, t, _)| *t == SymbolType::Data)
+                    .map(|(name, _, offset)| (name.as_str(), *offset as usize))
+                    .collect();
+                for (offset, label, s) in strings(&self.vm.ro_data, &labels) {
+                    println!("{:#06x}  {:<12} {:?}", offset, label.unwrap_or(""), s);
+                }
+            }
+            ".poke" => match (
+                args.first().and_then(|m| memory(m)),
+                args.get(1).and_then(|a| self.parse_addr(a)),
+                self.parse_hex(&args.get(2..).unwrap_or_default().join(" ")),
+            ) {
+                (Some(m), Some(addr), Ok(bytes)) => {
+                    if self.vm.poke(m, addr, &bytes) {
+                        println!("Wrote {} bytes at {:#x}", bytes.len(), addr);
+                    } else {
+                        self.error(format!("There are {} bytes", self.vm.memory(m).len()));
+                    }
+                }
+                _ => self.error("Try .poke heap|ro ADDR BYTES, with the bytes in hex".to_string()),
+            },
+            ".clear_program" => {
+                self.vm.program_mut().clear();
+                self.source.clear();
+                self.assembled.clear();
+            }
+            ".load_file" => {
+                if args.is_empty() {
+                    self.error("No filename specified".to_string());
+                    return Flow::Continue;
+                }
+                println!("Loading {}", args[0]);
+                self.asm.file = args[0].to_string();
+                match std::fs::read(args[0]) {
+                    Ok(data) => self.load_bytes(&data),
+                    Err(e) => {
+                        self.error(format!("Error reading the file: {}", e));
+                    }
+                }
+            }
+            ".source" => match args.first() {
+                Some(file) => return self.source(file),
+                None => self.error("No filename specified".to_string()),
+            },
+            ".events" => match args.first() {
+                // JSON lines, to a file for monitoring to pick up
+                Some(file) => match std::fs::File::create(file)
+                    .and_then(|mut f| self.vm.write_events(&mut f))
+                {
+                    Ok(()) => println!("Wrote {} events to {}", self.vm.events().len(), file),
+                    Err(e) => self.error(format!("Error writing the file: {}", e)),
+                },
+                None => {
+                    for e in self.vm.events() {
+                        println!("{}", e.to_json());
                     }
                 }
-                _ => {
-                    println!("Invalid input <{}>. Try the .help command", input)
+            },
+            ".save_vm" => {
+                if args.is_empty() {
+                    self.error("No filename specified".to_string());
+                    return Flow::Continue;
+                }
+                match std::fs::write(args[0], self.vm.snapshot()) {
+                    Ok(()) => println!("Saved VM to {}", args[0]),
+                    Err(e) => self.error(format!("Error writing the file: {}", e)),
+                }
+            }
+            ".load_vm" => {
+                if args.is_empty() {
+                    self.error("No filename specified".to_string());
+                    return Flow::Continue;
+                }
+                match std::fs::read(args[0]).map(|data| self.vm.restore(&data)) {
+                    Ok(Ok(())) => println!("Restored VM from {}", args[0]),
+                    Ok(Err(SnapshotError::UnsupportedVersion(v))) => self.error(format!(
+                        "Cannot restore VM, the snapshot has version {} but this rvm reads version {}",
+                        v, SNAPSHOT_VERSION
+                    )),
+                    Ok(Err(e)) => self.error(format!("Cannot restore VM, {:?}", e)),
+                    Err(e) => self.error(format!("Error reading the file: {}", e)),
                 }
             }
-            self.cmd.push(input.to_string());
+            // assembly, and the section directives
+            _ if !input.starts_with('.') || input == ".data" || input == ".code" => {
+                self.add_source(input)
+            }
+            _ => self.error(format!("Invalid input <{}>. Try the .help command", input)),
+        }
+        Flow::Continue
+    }
+
+    /// reports a command that failed
+    fn error(&mut self, message: String) {
+        println!("{}", message);
+        self.failures += 1;
+    }
+
+    /// the registers, flags, pc and heap of the VM
+    fn registers(&self, radix: Radix) -> String {
+        let vm = &self.vm;
+        let mut out = String::from("Registers:\n");
+        for (i, reg) in vm.regs.iter().enumerate() {
+            out.push_str(&format!(
+                "{:<5}{:<14}",
+                format!("${}", i),
+                radix.format(*reg)
+            ));
+            if i % 4 == 3 {
+                out.truncate(out.trim_end().len());
+                out.push('\n');
+            }
+        }
+        out.push_str("Float registers:\n");
+        for (i, reg) in vm.fregs.iter().enumerate() {
+            out.push_str(&format!("{:<5}{:<14}", format!("$f{}", i), reg));
+            if i % 4 == 3 {
+                out.truncate(out.trim_end().len());
+                out.push('\n');
+            }
+        }
+        let stats = vm.heap_stats();
+        out.push_str(&format!(
+            "pc: {}\nremainder: {}\nflags: eq={} zero={} negative={} carry={} overflow={}\n\
+             sp: none, the VM has no stack\n\
+             heap: {} bytes, {} in use in {} blocks\n",
+            vm.describe_pc(vm.pc),
+            radix.format(vm.remainder as i32),
+            vm.bool_flag as u8,
+            vm.flags.zero as u8,
+            vm.flags.negative as u8,
+            vm.flags.carry as u8,
+            vm.flags.overflow as u8,
+            stats.heap_size,
+            stats.in_use,
+            stats.live_blocks
+        ));
+        out
+    }
+
+    fn editor() -> rustyline::Result<Editor<ReplHelper, DefaultHistory>> {
+        let config = Config::builder()
+            .max_history_size(HISTORY_SIZE)?
+            .auto_add_history(false)
+            .build();
+        let mut editor = Editor::with_config(config)?;
+        editor.set_helper(Some(ReplHelper::default()));
+        Ok(editor)
+    }
+
+    fn save_history(editor: &mut Editor<ReplHelper, DefaultHistory>) {
+        if let Some(file) = history_file() {
+            if let Err(e) = editor.save_history(&file) {
+                println!("Cannot save the history to {}: {}", file.display(), e);
+            }
+        }
+    }
+
+    /// runs the VM on a scheduler thread until it stops or pauses, then keeps it
+    fn run_vm(&mut self) {
+        // a Ctrl-C from before the run isn't for it
+        self.vm.interrupt.store(false, Ordering::Relaxed);
+        let events = self.vm.events().len();
+        self.running.store(true, Ordering::Relaxed);
+        let joined = self.sched.get_thread(self.vm.clone()).join();
+        self.running.store(false, Ordering::Relaxed);
+        match joined {
+            Ok((vm, exit)) => {
+                self.vm = vm;
+                match exit {
+                    vm::VMExit::Halted => {}
+                    vm::VMExit::Paused => self.report_pause(events),
+                    vm::VMExit::Faulted => {
+                        let message = match &self.vm.fault {
+                            Some(f) => format!("Faulted at {}: {}", self.vm.describe_pc(f.pc()), f),
+                            None => "Faulted".to_string(),
+                        };
+                        self.error(message)
+                    }
+                    vm::VMExit::OutOfFuel => self.error(format!(
+                        "Out of fuel at {}",
+                        self.vm.describe_pc(self.vm.pc)
+                    )),
+                    vm::VMExit::TimedOut => {
+                        self.error(format!("Timed out at {}", self.vm.describe_pc(self.vm.pc)))
+                    }
+                    vm::VMExit::Interrupted => println!(
+                        "Interrupted at {}, .continue resumes",
+                        self.vm.describe_pc(self.vm.pc)
+                    ),
+                }
+            }
+            Err(_) => self.error("The VM thread panicked".to_string()),
+        }
+    }
+
+    /// prints the breakpoints and watchpoints hit since the VM had `events` events
+    fn report_pause(&self, events: usize) {
+        for e in self.vm.events().iter().skip(events) {
+            match &e.event {
+                VMEventType::Breakpoint(pc) => println!("Paused at breakpoint, pc {:#x}", pc),
+                VMEventType::Watchpoint(pc, w, old, new) => println!(
+                    "Paused after {:#x}, {} changed from {} to {}",
+                    pc,
+                    w,
+                    old.map_or("nothing".to_string(), |v| v.to_string()),
+                    new.map_or("nothing".to_string(), |v| v.to_string())
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    /// a decimal or 0x prefixed hex address, or @label
+    fn parse_addr(&self, s: &str) -> Option<usize> {
+        if let Some(label) = s.strip_prefix('@') {
+            return self.asm.symbols.symbol_value(label).map(|v| v as usize);
+        }
+        match s.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        }
+    }
+
+    /// a tracer from `.trace on` options, in any order
+    fn tracer(&self, opts: &[&str]) -> Result<Tracer, String> {
+        let (mut format, mut file, mut range) = (TraceFormat::Text, None, None);
+        for &opt in opts {
+            if let Ok(f) = opt.parse() {
+                format = f;
+            } else if opt.contains("..") {
+                let r = parse_range(opt, |a| self.parse_addr(a));
+                range = Some(r.ok_or(format!("Invalid range {}", opt))?);
+            } else {
+                file = Some(opt);
+            }
+        }
+        let mut tracer = match file {
+            Some(file) => {
+                let f = std::fs::File::create(file)
+                    .map_err(|e| format!("Error creating the file: {}", e))?;
+                Tracer::new(format, io::BufWriter::new(f))
+            }
+            None => Tracer::stdout(format),
+        };
+        tracer.range = range;
+        Ok(tracer)
+    }
+
+    /// `$N` or `heap[ADDR]`
+    fn parse_watch(&self, s: &str) -> Option<Watch> {
+        if let Some(reg) = s.strip_prefix('$') {
+            return reg.parse().ok().filter(|&r| r < 32).map(Watch::Reg);
+        }
+        let addr = s.strip_prefix("heap[")?.strip_suffix(']')?;
+        self.parse_addr(addr).map(Watch::Heap)
+    }
+
+    /// an assembler for the current file, without the symbols of earlier
+    /// programs
+    fn assembler(&self) -> Assembler {
+        let mut asm = Assembler::new();
+        asm.debug = true;
+        asm.file = self.asm.file.clone();
+        asm
+    }
+
+    /// whether all of `text` is assembly, the assembler stops at the first
+    /// line it can't read
+    fn parses(text: &str) -> Result<(), String> {
+        match program(text) {
+            Ok((rest, _)) if rest.trim().is_empty() => Ok(()),
+            Ok((rest, _)) => Err(format!("Cannot parse <{}>", rest.trim())),
+            Err(e) => Err(format!("Cannot parse, {}", e)),
         }
     }
 
-    fn verify_header(&self, bytes: &Vec<u8>) -> bool {
+    /// appends assembly to the program and reassembles it, keeping the
+    /// registers and where the VM is; in eval mode runs the new code
+    fn add_source(&mut self, text: &str) {
+        if self.vm.program() != self.assembled {
+            self.error(
+                "The program was changed with .instruct or .load_vm, .clear_program first"
+                    .to_string(),
+            );
+            return;
+        }
+        if let Err(e) = Self::parses(text) {
+            self.error(e.to_string());
+            return;
+        }
+        let mut source = self.source.clone();
+        if source.is_empty() {
+            self.asm.file = "<repl>".to_string();
+            // so a first instruction doesn't need a section
+            if !text.starts_with(".data") && !text.starts_with(".code") {
+                source.push_str(".code\n");
+            }
+        }
+        source.push_str(text);
+        source.push('\n');
+        let mut asm = self.assembler();
+        let prog = match asm.assemble(&source) {
+            Ok(prog) => prog,
+            // a .data line alone has no code yet
+            Err(errors) if errors.is_empty() => {
+                self.source = source;
+                return;
+            }
+            Err(errors) => {
+                for e in errors {
+                    self.error(format!("Cannot assemble, {:?}", e));
+                }
+                return;
+            }
+        };
+        let old =
+            PieHeader::parse(self.vm.program()).map(|h| (h.code_offset, self.vm.program().len()));
+        let pc = self.vm.pc;
+        match self.vm.load(prog, asm.ro.clone()) {
+            Ok(()) => {}
+            Err(vm::LoadError::Invalid(diagnostics)) => {
+                self.error("Cannot load program, it doesn't verify:".to_string());
+                for d in diagnostics {
+                    println!("\t{:?}", d);
+                }
+                return;
+            }
+            Err(e) => {
+                self.error(format!("Cannot load program, {:?}", e));
+                return;
+            }
+        }
+        // new imports move the code
+        let offset = self.vm.pc;
+        let new_code = match old {
+            Some((old_offset, old_end)) => {
+                self.vm.pc = (pc.max(old_offset) - old_offset) + offset;
+                old_end - old_offset + offset
+            }
+            None => offset,
+        };
+        self.source = source;
+        self.assembled = self.vm.program().to_vec();
+        self.asm = asm;
+        if self.eval && new_code < self.vm.program().len() {
+            self.vm.pc = new_code;
+            self.run_vm();
+        }
+    }
+
+    fn load_bytes(&mut self, bytes: &[u8]) {
+        if !self.verify_header(bytes) {
+            self.error("Wrong file or missing magic bytes".to_string());
+            return;
+        }
+        let source = String::from_utf8_lossy(&bytes[PIE_HEADER_LENGTH..]).into_owned();
+        let mut asm = self.assembler();
+        match asm.assemble(&source) {
+            Ok(prog) => match self.vm.load(prog, asm.ro.clone()) {
+                Ok(()) => {
+                    println!("Parsed.");
+                    self.source = source;
+                    self.assembled = self.vm.program().to_vec();
+                    self.asm = asm;
+                }
+                Err(vm::LoadError::Invalid(diagnostics)) => {
+                    self.error("Cannot load program, it doesn't verify:".to_string());
+                    for d in diagnostics {
+                        println!("\t{:?}", d);
+                    }
+                }
+                Err(e) => self.error(format!("Cannot load program, {:?}", e)),
+            },
+            Err(e) => {
+                self.error(format!("Cannot parse file, {:#?}", e));
+            }
+        }
+    }
+
+    fn verify_header(&self, bytes: &[u8]) -> bool {
         bytes.len() > PIE_HEADER_PREFIX.len()
             && bytes[0..PIE_HEADER_PREFIX.len()] == PIE_HEADER_PREFIX
     }
@@ -184,7 +850,7 @@ impl REPL {
         let split = i.split(" ").collect::<Vec<&str>>();
         let mut res: Vec<u8> = vec![];
         for s in split {
-            match u8::from_str_radix(&s, 0x10) {
+            match u8::from_str_radix(s, 0x10) {
                 Ok(byte) => res.push(byte),
                 Err(e) => return Err(e),
             }
@@ -192,3 +858,204 @@ impl REPL {
         Ok(res)
     }
 }
+
+/// `heap` or `ro`
+fn memory(s: &str) -> Option<Memory> {
+    match s {
+        "heap" => Some(Memory::Heap),
+        "ro" => Some(Memory::Ro),
+        _ => None,
+    }
+}
+
+/// 16 bytes per line with their offset, starting at `start`, and as text
+fn hexdump(bytes: &[u8], start: usize) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        out.push_str(&format!("{:#06x} ", start + i * 16));
+        for (j, b) in line.iter().enumerate() {
+            // a gap after 8 bytes
+            out.push_str(if j == 8 { "  " } else { " " });
+            out.push_str(&format!("{:02x}", b));
+        }
+        let missing = 16 - line.len();
+        out.push_str(&" ".repeat(missing * 3 + (missing >= 8) as usize));
+        let text: String = line
+            .iter()
+            .map(|&b| match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            })
+            .collect();
+        out.push_str(&format!("  |{}|\n", text));
+    }
+    out
+}
+
+/// the null-terminated strings in `ro`: the ones at `labels`, and runs of
+/// at least 4 printable characters elsewhere, with their offsets
+fn strings<'a>(ro: &[u8], labels: &[(&'a str, usize)]) -> Vec<(usize, Option<&'a str>, String)> {
+    let label_at = |i: usize| labels.iter().find(|&&(_, at)| at == i).map(|&(l, _)| l);
+    let mut found = vec![];
+    let mut i = 0;
+    while i < ro.len() {
+        let end = ro[i..]
+            .iter()
+            .position(|&b| b == 0)
+            .map_or(ro.len(), |n| i + n);
+        if let Some(label) = label_at(i) {
+            found.push((
+                i,
+                Some(label),
+                String::from_utf8_lossy(&ro[i..end]).into_owned(),
+            ));
+            i = end + 1;
+            continue;
+        }
+        let printable = ro[i..end].iter().all(|b| (0x20..=0x7e).contains(b));
+        let labeled_inside = (i + 1..end).any(|j| label_at(j).is_some());
+        if end < ro.len() && end - i >= 4 && printable && !labeled_inside {
+            found.push((i, None, String::from_utf8_lossy(&ro[i..end]).into_owned()));
+            i = end + 1;
+        } else {
+            i += 1;
+        }
+    }
+    found
+}
+
+/// `.rvm_history` in the home directory
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rvm_history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_add_source() {
+        let mut repl = REPL::new();
+        repl.add_source("load $0 #5");
+        assert_eq!(repl.vm.regs[0], 0);
+        assert_eq!(repl.vm.program().len(), PIE_HEADER_LENGTH + 4);
+        repl.eval = true;
+        repl.add_source("inc $0");
+        // only the new instruction ran
+        assert_eq!(repl.vm.regs[0], 1);
+        assert_eq!(repl.vm.pc, PIE_HEADER_LENGTH + 8);
+
+        repl.add_source(".data");
+        repl.add_source("msg: .asciiz 'hi'");
+        repl.add_source(".code");
+        repl.add_source("load $1 @msg");
+        assert_eq!(repl.vm.ro_data, b"hi\0");
+
+        // a block can jump ahead
+        repl.add_source("br @end\nload $2 #9\nend: load $3 #7");
+        assert_eq!((repl.vm.regs[2], repl.vm.regs[3]), (0, 7));
+        assert_eq!(repl.parse_addr("@end"), Some(PIE_HEADER_LENGTH + 20));
+        let end = repl.vm.program().len();
+
+        // bad lines leave the program alone
+        repl.add_source("br @nowhere");
+        repl.add_source("load $0 #1 $2 $3");
+        assert_eq!(repl.vm.program().len(), end);
+        assert!(REPL::parses("load $0 #1\n$$").is_err());
+
+        repl.vm.program_mut().push(0);
+        repl.add_source("inc $0");
+        assert_eq!(repl.vm.program().len(), end + 1);
+    }
+    #[test]
+    fn test_batch() {
+        let mut repl = REPL::new();
+        let input = "load $0 #5\n.asm\ninc $0\n\n.bogus\n.run\n.quit\n.bogus\n";
+        assert_eq!(repl.batch(input.as_bytes()), Flow::Quit);
+        assert_eq!(repl.vm.regs[0], 6);
+        // nothing after .quit ran
+        assert_eq!(repl.failures, 1);
+
+        // an unfinished block ends with the input
+        let mut repl = REPL::new();
+        assert_eq!(
+            repl.batch("load $0 #5\n.asm\ninc $0".as_bytes()),
+            Flow::Continue
+        );
+        assert_eq!(repl.vm.program().len(), PIE_HEADER_LENGTH + 8);
+    }
+    #[test]
+    fn test_source() {
+        let path = std::env::temp_dir().join(format!("rvm-test-{}.rvmrc", std::process::id()));
+        let file = path.to_str().unwrap();
+        std::fs::write(
+            &path,
+            "# a comment\n.asm\nload $0 #1\n# inside\ninc $0\n\n.run\n",
+        )
+        .unwrap();
+        let mut repl = REPL::new();
+        assert_eq!(repl.execute(&format!(".source {}", file)), Flow::Continue);
+        assert_eq!((repl.vm.regs[0], repl.failures), (2, 0));
+
+        // a file sourcing itself stops
+        std::fs::write(&path, format!(".source {}\n", file)).unwrap();
+        repl.execute(&format!(".source {}", file));
+        assert_eq!(repl.failures, 1);
+        std::fs::remove_file(&path).unwrap();
+        repl.execute(&format!(".source {}", file));
+        assert_eq!(repl.failures, 2);
+    }
+    #[test]
+    fn test_hexdump() {
+        let bytes: Vec<u8> = (b'a'..=b'z').chain([0, 0xff]).collect();
+        let dump = hexdump(&bytes, 0x10);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines[0],
+            "0x0010  61 62 63 64 65 66 67 68  69 6a 6b 6c 6d 6e 6f 70  |abcdefghijklmnop|"
+        );
+        assert_eq!(
+            lines[1],
+            "0x0020  71 72 73 74 75 76 77 78  79 7a 00 ff              |qrstuvwxyz..|"
+        );
+        assert_eq!(
+            hexdump(b"hi", 0),
+            format!("0x0000  68 69{}  |hi|\n", " ".repeat(43))
+        );
+        assert_eq!(hexdump(&[], 0), "");
+    }
+    #[test]
+    fn test_strings() {
+        let mut ro = b"hi\0".to_vec();
+        ro.extend_from_slice(&1.5f64.to_be_bytes());
+        ro.extend_from_slice(b"\x01long one\0abc\0x");
+        let found = strings(&ro, &[("msg", 0)]);
+        assert_eq!(
+            found,
+            vec![
+                (0, Some("msg"), "hi".to_string()),
+                (12, None, "long one".to_string()),
+            ]
+        );
+    }
+    #[test]
+    fn test_registers() {
+        let mut repl = REPL::new();
+        repl.vm.regs[1] = -2;
+        repl.vm.fregs[4] = 1.5;
+        repl.vm.flags.carry = true;
+        let out = repl.registers(Radix::Signed);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[1],
+            "$0   0             $1   -2            $2   0             $3   0"
+        );
+        assert!(lines[11].starts_with("$f4  1.5 "));
+        assert!(out.contains("pc: 0x0\n"));
+        assert!(out.contains("flags: eq=0 zero=0 negative=0 carry=1 overflow=0\n"));
+        assert!(out.ends_with("heap: 0 bytes, 0 in use in 0 blocks\n"));
+        assert!(repl
+            .registers(Radix::Decimal)
+            .contains("$1   4294967294    $2"));
+        assert!(repl.registers(Radix::Hex).contains("$1   0xfffffffe    $2"));
+    }
+}
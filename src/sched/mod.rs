@@ -1,4 +1,4 @@
-use crate::vm::VM;
+use crate::vm::{VMExit, VM};
 use std::thread;
 
 #[derive(Default, Debug)]
@@ -11,15 +11,16 @@ impl Scheduler {
     pub fn new() -> Scheduler {
         Scheduler {
             next_pid: 0,
-            max_pid: std::u32::MAX,
+            max_pid: u32::MAX,
         }
     }
 
-    pub fn get_thread(&mut self, mut vm: VM) -> thread::JoinHandle<VM> {
+    /// runs `vm` on a new thread, which returns it with how it stopped
+    pub fn get_thread(&mut self, mut vm: VM) -> thread::JoinHandle<(VM, VMExit)> {
         self.next_pid = self.next_pid.wrapping_add(1) % self.max_pid;
         thread::spawn(move || {
-            vm.run();
-            vm
+            let exit = vm.run();
+            (vm, exit)
         })
     }
 }
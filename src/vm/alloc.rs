@@ -0,0 +1,429 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use super::snapshot::{Reader, SnapshotError, Writer};
+
+/// Written over freed blocks in debug mode; a block that doesn't hold it
+/// anymore was written after being freed.
+pub const POISON: u8 = 0xdd;
+/// bytes of freed blocks kept out of reuse in debug mode
+pub const QUARANTINE_BYTES: usize = 64 * 1024;
+
+/// Hands out blocks of the VM heap. Block metadata lives here rather than in
+/// the heap, so a program can't corrupt it.
+///
+/// Freed blocks go on an address ordered free list and are reused first fit.
+/// In debug mode they are poisoned and quarantined first, so a second free
+/// of the same address and writes to freed memory can be detected. Once the
+/// quarantine holds more than `quarantine_bytes` the oldest blocks move to
+/// the free list. A write to a freed block is reported by the ALOC or FREE
+/// that evicts or reuses it, not by the write itself.
+#[derive(Clone, Debug)]
+pub struct Allocator {
+    pub debug: bool,
+    pub quarantine_bytes: usize,
+    blocks: BTreeMap<usize, usize>, // live blocks, address -> size
+    free: Vec<(usize, usize)>,      // address, size; sorted and coalesced
+    quarantine: VecDeque<(usize, usize)>, // address, size; oldest first
+    allocations: u64,
+    frees: u64,
+    pub(super) journal: Option<Journal>, // kept while reverse debugging records a step
+}
+
+/// What `alloc` and `free` changed while journaling, enough to undo it
+#[derive(Clone, Debug, Default)]
+pub struct Journal {
+    pub heap: Vec<(usize, Vec<u8>)>, // address, the bytes before they were written
+    ops: Vec<Op>,
+}
+
+/// One `alloc` or `free`; a freed block went to the quarantine in debug mode
+/// and may have pushed older blocks out of it onto the free list
+#[derive(Clone, Debug)]
+enum Op {
+    Alloc(usize, usize, bool), // address, size, whether it came from the free list
+    Free(usize, usize, bool, Vec<(usize, usize)>), // address, size, quarantined, evicted blocks
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AllocError {
+    HeapLimit(usize),   // heap size the allocation needed
+    InvalidFree(usize), // address
+    DoubleFree(usize),
+    UseAfterFree(usize), // address of the freed block that was written
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    pub in_use: usize,
+    pub free: usize,
+    pub live_blocks: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Allocator {
+    pub fn new() -> Allocator {
+        Allocator {
+            debug: cfg!(debug_assertions),
+            quarantine_bytes: QUARANTINE_BYTES,
+            blocks: BTreeMap::new(),
+            free: vec![],
+            quarantine: VecDeque::new(),
+            allocations: 0,
+            frees: 0,
+            journal: None,
+        }
+    }
+
+    /// returns the address of a new zeroed block of `size` bytes, growing the
+    /// heap if no free block is large enough but never past `max_heap`
+    pub fn alloc(
+        &mut self,
+        heap: &mut Vec<u8>,
+        size: usize,
+        max_heap: Option<usize>,
+    ) -> Result<usize, AllocError> {
+        let size = size.max(1);
+        let addr = match self.free.iter().position(|&(_, len)| len >= size) {
+            Some(idx) => {
+                let (addr, len) = self.free[idx];
+                if self.debug {
+                    check_poison(heap, addr, size)?;
+                }
+                if len == size {
+                    self.free.remove(idx);
+                } else {
+                    self.free[idx] = (addr + size, len - size);
+                }
+                self.log_heap(heap, addr, size);
+                heap[addr..addr + size].fill(0);
+                self.log(Op::Alloc(addr, size, true));
+                addr
+            }
+            None => {
+                let addr = heap.len();
+                let end = addr.saturating_add(size);
+                if max_heap.is_some_and(|max| end > max) {
+                    return Err(AllocError::HeapLimit(end));
+                }
+                heap.resize(end, 0);
+                self.log(Op::Alloc(addr, size, false));
+                addr
+            }
+        };
+        self.blocks.insert(addr, size);
+        self.allocations += 1;
+        Ok(addr)
+    }
+
+    pub fn free(&mut self, heap: &mut [u8], addr: usize) -> Result<(), AllocError> {
+        let size = match self.blocks.remove(&addr) {
+            Some(size) => size,
+            None if self.quarantine.iter().any(|&(a, _)| a == addr) => {
+                return Err(AllocError::DoubleFree(addr))
+            }
+            None => return Err(AllocError::InvalidFree(addr)),
+        };
+        self.frees += 1;
+        if !self.debug {
+            self.release(addr, size);
+            self.log(Op::Free(addr, size, false, vec![]));
+            return Ok(());
+        }
+        self.log_heap(heap, addr, size);
+        heap[addr..addr + size].fill(POISON);
+        self.quarantine.push_back((addr, size));
+        let mut held: usize = self.quarantine.iter().map(|&(_, size)| size).sum();
+        let (mut evicted, mut res) = (vec![], Ok(()));
+        while res.is_ok() && held > self.quarantine_bytes {
+            let (addr, size) = self.quarantine.pop_front().unwrap();
+            held -= size;
+            // released either way, so the fault isn't reported again
+            self.release(addr, size);
+            evicted.push((addr, size));
+            res = check_poison(heap, addr, size);
+        }
+        self.log(Op::Free(addr, size, true, evicted));
+        res
+    }
+
+    /// undoes the allocator changes in `journal`; its heap bytes are for the
+    /// caller to put back
+    pub fn undo(&mut self, journal: Journal) {
+        for op in journal.ops.into_iter().rev() {
+            match op {
+                Op::Alloc(addr, size, reused) => {
+                    self.blocks.remove(&addr);
+                    self.allocations -= 1;
+                    if reused {
+                        self.release(addr, size);
+                    }
+                }
+                Op::Free(addr, size, quarantined, evicted) => {
+                    for &(addr, size) in evicted.iter().rev() {
+                        self.reclaim(addr, size);
+                        self.quarantine.push_front((addr, size));
+                    }
+                    if quarantined {
+                        self.quarantine.pop_back();
+                    } else {
+                        self.reclaim(addr, size);
+                    }
+                    self.blocks.insert(addr, size);
+                    self.frees -= 1;
+                }
+            }
+        }
+    }
+
+    fn log(&mut self, op: Op) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.ops.push(op);
+        }
+    }
+
+    fn log_heap(&mut self, heap: &[u8], addr: usize, size: usize) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.heap.push((addr, heap[addr..addr + size].to_vec()));
+        }
+    }
+
+    /// puts a block on the free list
+    fn release(&mut self, addr: usize, size: usize) {
+        let idx = self.free.partition_point(|&(a, _)| a < addr);
+        self.free.insert(idx, (addr, size));
+        // merge with the following block, then with the preceding one
+        if idx + 1 < self.free.len() && addr + size == self.free[idx + 1].0 {
+            self.free[idx].1 += self.free.remove(idx + 1).1;
+        }
+        if idx > 0 && self.free[idx - 1].0 + self.free[idx - 1].1 == addr {
+            self.free[idx - 1].1 += self.free.remove(idx).1;
+        }
+    }
+
+    /// takes a released block back out of the free list, splitting the free
+    /// block it was merged into
+    fn reclaim(&mut self, addr: usize, size: usize) {
+        let idx = self.free.partition_point(|&(a, _)| a <= addr) - 1;
+        let (start, len) = self.free.remove(idx);
+        let pieces = [
+            (start, addr - start),
+            (addr + size, start + len - addr - size),
+        ];
+        for (i, &piece) in pieces.iter().filter(|p| p.1 > 0).enumerate() {
+            self.free.insert(idx + i, piece);
+        }
+    }
+
+    /// the size of the live block starting at `addr`
+    pub fn block_size(&self, addr: usize) -> Option<usize> {
+        self.blocks.get(&addr).copied()
+    }
+
+    pub fn stats(&self, heap: &[u8]) -> HeapStats {
+        let in_use = self.blocks.values().sum();
+        HeapStats {
+            heap_size: heap.len(),
+            in_use,
+            // the program can shrink the heap under live blocks
+            free: heap.len().saturating_sub(in_use),
+            live_blocks: self.blocks.len(),
+            allocations: self.allocations,
+            frees: self.frees,
+        }
+    }
+
+    pub fn snapshot(&self, w: &mut Writer) {
+        w.bool(self.debug);
+        w.u64(self.quarantine_bytes as u64);
+        w.u64(self.allocations);
+        w.u64(self.frees);
+        w.u64(self.blocks.len() as u64);
+        for (&addr, &size) in &self.blocks {
+            w.u64(addr as u64);
+            w.u64(size as u64);
+        }
+        for blocks in [&self.quarantine, &VecDeque::from(self.free.clone())] {
+            w.u64(blocks.len() as u64);
+            for &(addr, size) in blocks {
+                w.u64(addr as u64);
+                w.u64(size as u64);
+            }
+        }
+    }
+
+    pub fn restore(r: &mut Reader) -> Result<Allocator, SnapshotError> {
+        let mut a = Allocator {
+            debug: r.bool()?,
+            quarantine_bytes: r.usize()?,
+            allocations: r.u64()?,
+            frees: r.u64()?,
+            ..Allocator::new()
+        };
+        for _ in 0..r.u64()? {
+            a.blocks.insert(r.usize()?, r.usize()?);
+        }
+        for _ in 0..r.u64()? {
+            a.quarantine.push_back((r.usize()?, r.usize()?));
+        }
+        for _ in 0..r.u64()? {
+            a.free.push((r.usize()?, r.usize()?));
+        }
+        Ok(a)
+    }
+
+    /// whether all the blocks are inside a heap of `len` bytes
+    pub fn fits(&self, len: usize) -> bool {
+        self.end().is_some_and(|end| end <= len)
+    }
+
+    /// the end of the highest block, live, freed or quarantined: the heap
+    /// can't shrink below it. None if a block's end overflows.
+    pub fn end(&self) -> Option<usize> {
+        self.blocks
+            .iter()
+            .map(|(&addr, &size)| (addr, size))
+            .chain(self.quarantine.iter().copied())
+            .chain(self.free.iter().copied())
+            .try_fold(0, |end: usize, (addr, size)| {
+                addr.checked_add(size).map(|e| end.max(e))
+            })
+    }
+}
+
+/// whether the freed bytes at `addr` still hold the poison
+fn check_poison(heap: &[u8], addr: usize, size: usize) -> Result<(), AllocError> {
+    let block = heap.get(addr..addr + size).unwrap_or_default();
+    if block.iter().any(|&b| b != POISON) {
+        return Err(AllocError::UseAfterFree(addr));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_alloc_reuses_freed_blocks() {
+        let mut heap = vec![];
+        let mut a = Allocator::new();
+        a.debug = false;
+        let x = a.alloc(&mut heap, 8, None).unwrap();
+        let y = a.alloc(&mut heap, 8, None).unwrap();
+        let z = a.alloc(&mut heap, 8, None).unwrap();
+        assert_eq!((x, y, z), (0, 8, 16));
+        heap[8] = 42;
+        a.free(&mut heap, x).unwrap();
+        a.free(&mut heap, y).unwrap();
+        // x and y were merged into one 16 bytes block
+        assert_eq!(a.alloc(&mut heap, 12, None), Ok(0));
+        assert_eq!(heap[8], 0);
+        assert_eq!(a.alloc(&mut heap, 4, None), Ok(12));
+        assert_eq!(heap.len(), 24);
+        assert_eq!(a.free(&mut heap, 3), Err(AllocError::InvalidFree(3)));
+        assert_eq!(
+            a.stats(&heap),
+            HeapStats {
+                heap_size: 24,
+                in_use: 24,
+                free: 0,
+                live_blocks: 3,
+                allocations: 5,
+                frees: 2,
+            }
+        );
+        heap.truncate(10);
+        assert_eq!((a.stats(&heap).in_use, a.stats(&heap).free), (24, 0));
+    }
+    #[test]
+    fn test_alloc_heap_limit() {
+        let mut heap = vec![];
+        let mut a = Allocator::new();
+        assert_eq!(a.alloc(&mut heap, 8, Some(8)), Ok(0));
+        assert_eq!(
+            a.alloc(&mut heap, 1, Some(8)),
+            Err(AllocError::HeapLimit(9))
+        );
+    }
+    #[test]
+    fn test_debug_detects_misuse() {
+        let mut heap = vec![];
+        let mut a = Allocator::new();
+        a.debug = true;
+        let x = a.alloc(&mut heap, 4, None).unwrap();
+        a.free(&mut heap, x).unwrap();
+        assert_eq!(a.free(&mut heap, x), Err(AllocError::DoubleFree(x)));
+        // quarantined blocks are not reused
+        assert_eq!(a.alloc(&mut heap, 4, None), Ok(4));
+        heap[x + 1] = 7;
+        // found when the block leaves the quarantine
+        a.quarantine_bytes = 4;
+        assert_eq!(a.free(&mut heap, 4), Err(AllocError::UseAfterFree(x)));
+        assert_eq!(a.free(&mut heap, x), Err(AllocError::InvalidFree(x)));
+    }
+    #[test]
+    fn test_quarantine_is_bounded() {
+        let mut heap = vec![];
+        let mut a = Allocator::new();
+        a.debug = true;
+        a.quarantine_bytes = 32;
+        for _ in 0..100 {
+            let x = a.alloc(&mut heap, 16, None).unwrap();
+            a.free(&mut heap, x).unwrap();
+        }
+        // two blocks in quarantine, the rest reused
+        assert_eq!(heap.len(), 48);
+        assert_eq!(a.quarantine.len(), 2);
+        // blocks are checked when reused and when evicted
+        let (p, r) = (a.quarantine[0].0, a.free[0].0);
+        heap[r] = 1;
+        assert_eq!(
+            a.alloc(&mut heap, 16, None),
+            Err(AllocError::UseAfterFree(r))
+        );
+        heap[r] = POISON;
+        let y = a.alloc(&mut heap, 16, None).unwrap();
+        assert_eq!(y, r);
+        heap[p] = 1;
+        assert_eq!(a.free(&mut heap, y), Err(AllocError::UseAfterFree(p)));
+    }
+    #[test]
+    fn test_undo() {
+        for debug in [false, true] {
+            let mut heap = vec![];
+            let mut a = Allocator::new();
+            a.debug = debug;
+            a.quarantine_bytes = 8;
+            let x = a.alloc(&mut heap, 8, None).unwrap();
+            let y = a.alloc(&mut heap, 8, None).unwrap();
+            a.alloc(&mut heap, 8, None).unwrap();
+            a.free(&mut heap, x).unwrap();
+            heap[y] = 5;
+            let (before, heap_before) = (state(&a), heap.clone());
+            // a free that merges with x, or evicts it from the quarantine,
+            // and an allocation reusing the free list
+            a.journal = Some(Journal::default());
+            a.free(&mut heap, y).unwrap();
+            assert_eq!(a.alloc(&mut heap, 4, None), Ok(x));
+            let journal = a.journal.take().unwrap();
+            a.undo(journal.clone());
+            for (addr, bytes) in journal.heap.into_iter().rev() {
+                heap[addr..addr + bytes.len()].copy_from_slice(&bytes);
+            }
+            assert_eq!((state(&a), heap), (before, heap_before));
+        }
+    }
+
+    fn state(a: &Allocator) -> Vec<u8> {
+        let mut w = Writer::default();
+        a.snapshot(&mut w);
+        w.bytes
+    }
+}
@@ -0,0 +1,292 @@
+use std::io;
+
+use super::decode::{disassemble, Insn};
+use crate::asm::PieHeader;
+use crate::instruction::Opcode;
+
+/// Which instructions ran and which way conditional jumps went, recorded
+/// by `VM::step` while the VM has one
+#[derive(Clone, Debug, Default)]
+pub struct Coverage {
+    pub hits: Vec<u64>,                   // executions by pc
+    pub branches: Vec<(usize, u64, u64)>, // pc, times taken, times not taken
+}
+
+/// A source line with code
+struct Line {
+    line: usize,
+    count: u64,                        // executions of its busiest instruction
+    branches: Vec<Option<(u64, u64)>>, // its conditional jumps, None if they never ran
+}
+
+impl Coverage {
+    /// `next` is the pc after the instruction at `pc` ran
+    pub(super) fn add(&mut self, pc: usize, insn: &Insn, next: usize) {
+        if pc >= self.hits.len() {
+            self.hits.resize(pc + 1, 0);
+        }
+        self.hits[pc] += 1;
+        if !insn.op.is_conditional_jump() {
+            return;
+        }
+        let i = match self.branches.iter().position(|b| b.0 == pc) {
+            Some(i) => i,
+            None => {
+                self.branches.push((pc, 0, 0));
+                self.branches.len() - 1
+            }
+        };
+        if next == pc + insn.len as usize {
+            self.branches[i].2 += 1;
+        } else {
+            self.branches[i].1 += 1;
+        }
+    }
+
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.hits.get(pc).copied().unwrap_or(0)
+    }
+
+    /// times the conditional jump at `pc` was and wasn't taken
+    pub fn branch(&self, pc: usize) -> Option<(u64, u64)> {
+        self.branches
+            .iter()
+            .find(|b| b.0 == pc)
+            .map(|&(_, taken, not_taken)| (taken, not_taken))
+    }
+
+    /// `lines` has the address, line and column of every instruction
+    fn lines(&self, lines: &[(usize, usize, usize)], program: &[u8]) -> Vec<Line> {
+        let mut by_line: Vec<Line> = vec![];
+        for &(pc, line, _) in lines {
+            let i = match by_line.iter().position(|l| l.line == line) {
+                Some(i) => i,
+                None => {
+                    by_line.push(Line {
+                        line,
+                        count: 0,
+                        branches: vec![],
+                    });
+                    by_line.len() - 1
+                }
+            };
+            let l = &mut by_line[i];
+            l.count = l.count.max(self.hits(pc));
+            if program
+                .get(pc)
+                .is_some_and(|&op| Opcode::from(op).is_conditional_jump())
+            {
+                l.branches.push(self.branch(pc));
+            }
+        }
+        by_line.sort_by_key(|l| l.line);
+        by_line
+    }
+
+    /// the source with how often each line ran, `#####` for code that never
+    /// did, and which way its conditional jumps went
+    pub fn annotate(
+        &self,
+        source: &str,
+        lines: &[(usize, usize, usize)],
+        program: &[u8],
+    ) -> String {
+        let by_line = self.lines(lines, program);
+        let mut out = String::new();
+        for (n, text) in source.lines().enumerate() {
+            let line = by_line.iter().find(|l| l.line == n + 1);
+            let count = match line {
+                Some(l) if l.count == 0 => "#####".to_string(),
+                Some(l) => l.count.to_string(),
+                None => "-".to_string(),
+            };
+            out.push_str(&format!("{:>9}: {:>4}: {}", count, n + 1, text));
+            for b in line.iter().flat_map(|l| &l.branches) {
+                match b {
+                    Some((taken, not_taken)) => {
+                        out.push_str(&format!("  [taken {}, not taken {}]", taken, not_taken))
+                    }
+                    None => out.push_str("  [never ran]"),
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// every instruction of `program` with how often it ran, for programs
+    /// without debug info to map them to source lines
+    pub fn annotate_pcs(&self, program: &[u8]) -> String {
+        let mut pc = PieHeader::parse(program).map_or(0, |h| h.code_offset);
+        let mut out = String::new();
+        while pc < program.len() {
+            let count = match self.hits(pc) {
+                0 => "#####".to_string(),
+                n => n.to_string(),
+            };
+            out.push_str(&format!(
+                "{:>9}: {:#06x}: {}",
+                count,
+                pc,
+                disassemble(program, pc, None)
+            ));
+            if Opcode::from(program[pc]).is_conditional_jump() {
+                match self.branch(pc) {
+                    Some((taken, not_taken)) => {
+                        out.push_str(&format!("  [taken {}, not taken {}]", taken, not_taken))
+                    }
+                    None => out.push_str("  [never ran]"),
+                }
+            }
+            out.push('\n');
+            pc += 4;
+        }
+        out
+    }
+
+    /// an lcov tracefile for the source `file`
+    pub fn write_lcov<W: io::Write>(
+        &self,
+        w: &mut W,
+        file: &str,
+        lines: &[(usize, usize, usize)],
+        program: &[u8],
+    ) -> io::Result<()> {
+        let by_line = self.lines(lines, program);
+        writeln!(w, "TN:")?;
+        writeln!(w, "SF:{}", file)?;
+        let (mut found, mut hit) = (0, 0);
+        for l in &by_line {
+            for (block, b) in l.branches.iter().enumerate() {
+                let (taken, not_taken) = match b {
+                    Some((t, n)) => (t.to_string(), n.to_string()),
+                    None => ("-".to_string(), "-".to_string()),
+                };
+                writeln!(w, "BRDA:{},{},0,{}", l.line, block, taken)?;
+                writeln!(w, "BRDA:{},{},1,{}", l.line, block, not_taken)?;
+                found += 2;
+                hit += b.map_or(0, |(t, n)| (t > 0) as usize + (n > 0) as usize);
+            }
+        }
+        writeln!(w, "BRF:{}", found)?;
+        writeln!(w, "BRH:{}", hit)?;
+        for l in &by_line {
+            writeln!(w, "DA:{},{}", l.line, l.count)?;
+        }
+        writeln!(w, "LF:{}", by_line.len())?;
+        writeln!(w, "LH:{}", by_line.iter().filter(|l| l.count > 0).count())?;
+        writeln!(w, "end_of_record")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::vm::profile::Profile;
+    use crate::vm::tests::run;
+    use crate::vm::VM;
+    #[test]
+    fn test_coverage() {
+        let source = ".data
+.code
+load $0 #2
+load $2 #0
+top: dec $0
+eq $0 $2
+bne @top
+eq $0 $2
+beq @end
+hlt
+end: hlt";
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load(program, vec![]).unwrap();
+        vm.coverage = Some(Coverage::default());
+        run(&mut vm);
+        let coverage = vm.coverage.as_ref().unwrap();
+        let bne = asm.symbols.symbol_value("top").unwrap() as usize + 8;
+        assert_eq!(coverage.hits(bne), 2);
+        assert_eq!(coverage.branch(bne), Some((1, 1)));
+        assert_eq!(coverage.branch(bne + 8), Some((1, 0)));
+
+        let listing = coverage.annotate(source, &asm.lines, &vm.program);
+        let listing: Vec<&str> = listing.lines().collect();
+        assert_eq!(listing[0], "        -:    1: .data");
+        assert_eq!(listing[4], "        2:    5: top: dec $0");
+        assert_eq!(
+            listing[6],
+            "        2:    7: bne @top  [taken 1, not taken 1]"
+        );
+        assert_eq!(
+            listing[8],
+            "        1:    9: beq @end  [taken 1, not taken 0]"
+        );
+        assert_eq!(listing[9], "    #####:   10: hlt");
+
+        let mut lcov = vec![];
+        coverage
+            .write_lcov(&mut lcov, "t.asm", &asm.lines, &vm.program)
+            .unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:t.asm\nBRDA:7,0,0,1\nBRDA:7,0,1,1\nBRDA:9,0,0,1\nBRDA:9,0,1,0\nBRF:4\nBRH:3\nDA:3,1\n"));
+        assert!(lcov.ends_with("DA:10,0\nDA:11,1\nLF:9\nLH:8\nend_of_record\n"));
+    }
+    #[test]
+    fn test_coverage_stripped() {
+        let mut asm = Assembler::new();
+        asm.debug = false;
+        let program = asm
+            .assemble(
+                ".data
+.code
+load $0 #1
+top: dec $0
+eq $0 $1
+bne @top
+hlt",
+            )
+            .unwrap();
+        assert!(PieHeader::parse(&program).unwrap().debug.is_none());
+        let mut vm = VM::new();
+        vm.load(program, vec![]).unwrap();
+        assert!(vm.debug_info.is_none());
+        vm.coverage = Some(Coverage::default());
+        vm.profile = Some(Profile::default());
+        run(&mut vm);
+        let top = asm.symbols.symbol_value("top").unwrap() as usize;
+
+        let listing = vm.coverage.as_ref().unwrap().annotate_pcs(&vm.program);
+        assert_eq!(
+            listing.lines().collect::<Vec<_>>(),
+            vec![
+                format!("        1: {:#06x}: load $0 #1", top - 4),
+                format!("        1: {:#06x}: dec $0", top),
+                format!("        1: {:#06x}: eq $0 $1", top + 4),
+                format!(
+                    "        1: {:#06x}: bne -8  [taken 0, not taken 1]",
+                    top + 8
+                ),
+                format!("        1: {:#06x}: hlt", top + 12),
+            ]
+        );
+
+        let profile = vm.profile.as_ref().unwrap();
+        let report = profile.report(&vm.program, &asm.symbols, vm.debug_info.as_ref(), 5);
+        // the source column is empty
+        let address = format!("{:#06x}", top);
+        assert!(report.contains(&format!("{:<8} {:<16} {:<16} dec $0\n", address, "", "top")));
+        let mut folded = vec![];
+        profile
+            .write_folded(
+                &mut folded,
+                &vm.program,
+                &asm.symbols,
+                vm.debug_info.as_ref(),
+            )
+            .unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert!(folded.contains(&format!("top;{:#06x} dec $0 1\n", top)));
+    }
+}
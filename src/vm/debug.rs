@@ -0,0 +1,90 @@
+use std::fmt;
+
+/// A location a watchpoint looks at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Watch {
+    Reg(usize),
+    Heap(usize), // address of a byte
+}
+
+/// Memory the debugger can look at and change
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Memory {
+    Heap,
+    Ro, // read-only for programs, not for the debugger
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Point {
+    Break(usize),              // pc
+    Watch(Watch, Option<i32>), // the value last seen, None outside of the heap
+}
+
+/// Breakpoints and watchpoints, checked by `VM::step` while there are any.
+/// It lives in the VM, so it goes with it to the scheduler's threads.
+#[derive(Clone, Debug, Default)]
+pub struct Debugger {
+    pub points: Vec<(usize, Point)>, // number, point
+    next: usize,
+    pub(super) resume_at: Option<usize>, // breakpoint to run over after pausing on it
+    pub(super) paused: bool,
+}
+
+impl Debugger {
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// adds a point and returns its number
+    pub fn add(&mut self, point: Point) -> usize {
+        self.next += 1;
+        self.points.push((self.next, point));
+        self.next
+    }
+
+    /// removes point `n`, false if there is none
+    pub fn delete(&mut self, n: usize) -> bool {
+        let len = self.points.len();
+        self.points.retain(|&(m, _)| m != n);
+        self.points.len() != len
+    }
+
+    pub fn has_breakpoint(&self, pc: usize) -> bool {
+        self.points.iter().any(|(_, p)| *p == Point::Break(pc))
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Reg(r) => write!(f, "${}", r),
+            Watch::Heap(addr) => write!(f, "heap[{}]", addr),
+        }
+    }
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Point::Break(pc) => write!(f, "breakpoint at {:#x}", pc),
+            Point::Watch(w, _) => write!(f, "watchpoint on {}", w),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_points() {
+        let mut d = Debugger::default();
+        assert_eq!(d.add(Point::Break(8)), 1);
+        assert_eq!(d.add(Point::Watch(Watch::Heap(3), None)), 2);
+        assert!(d.has_breakpoint(8));
+        assert!(!d.has_breakpoint(4));
+        assert!(d.delete(1));
+        assert!(!d.delete(1));
+        assert_eq!(d.add(Point::Break(4)), 3);
+        assert_eq!(d.points[0].1.to_string(), "watchpoint on heap[3]");
+    }
+}
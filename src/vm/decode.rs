@@ -0,0 +1,255 @@
+use std::fmt;
+
+use crate::asm::debug::DebugInfo;
+use crate::instruction::Opcode;
+
+/// An instruction with its operand bytes already read and its registers
+/// checked, so executing it doesn't touch `program` again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Insn {
+    pub op: Opcode,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub len: u8, // bytes the instruction takes, pc moves by this unless it jumps
+}
+
+/// A register named by an instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reg {
+    Int(usize),
+    Float(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    BadRegister(u8),
+    Truncated,
+}
+
+impl Insn {
+    /// 16 bit immediate in the last two operand bytes
+    pub fn imm(&self) -> u16 {
+        (self.b as u16) << 8 | self.c as u16
+    }
+
+    /// 16 bit offset or id in the first two operand bytes
+    pub fn off(&self) -> u16 {
+        (self.a as u16) << 8 | self.b as u16
+    }
+
+    /// the registers in the operands, in order
+    pub fn regs(&self) -> Vec<Reg> {
+        use Opcode::*;
+        let (_, _, n) = layout(self.op);
+        [self.a, self.b, self.c][..n as usize]
+            .iter()
+            .enumerate()
+            .map(|(i, &r)| match (self.op, i) {
+                (ITOF, 0) | (FTOI, 1) => Reg::Int(r as usize),
+                (LOADF | ADDF | SUBF | MULF | DIVF | EQF | NEQF | GTF | GEQF | LTF | LEQF, _)
+                | (ITOF | FTOI, _) => Reg::Float(r as usize),
+                _ => Reg::Int(r as usize),
+            })
+            .collect()
+    }
+
+    /// the registers the instruction writes, unless it faults
+    pub fn writes(&self) -> Vec<Reg> {
+        use Opcode::*;
+        let regs = self.regs();
+        match self.op {
+            LOAD | LOADF | MOV | NEG | INC | DEC | NOT | ADDI | SUBI | MULI => vec![regs[0]],
+            ALOC | ITOF | FTOI => vec![regs[1]],
+            ADD | SUB | MUL | DIV | OR | AND | XOR | SHL | SHR | SAR | ROL | ROR | MOD | ADDF
+            | SUBF | MULF | DIVF => vec![regs[2]],
+            // host functions can write the others too
+            SYSCALL => vec![Reg::Int(0)],
+            _ => vec![],
+        }
+    }
+
+    /// the registers whose values the instruction uses
+    pub fn reads(&self) -> Vec<Reg> {
+        use Opcode::*;
+        // the operand that is only written, if any
+        let dst = match self.op {
+            LOAD | LOADF | MOV => Some(0),
+            ALOC | ITOF | FTOI => Some(1),
+            ADD | SUB | MUL | DIV | OR | AND | XOR | SHL | SHR | SAR | ROL | ROR | MOD | ADDF
+            | SUBF | MULF | DIVF => Some(2),
+            _ => None,
+        };
+        let mut regs = self.regs();
+        if let Some(dst) = dst {
+            regs.remove(dst);
+        }
+        regs
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reg::Int(r) => write!(f, "${}", r),
+            Reg::Float(r) => write!(f, "$f{}", r),
+        }
+    }
+}
+
+/// assembly syntax, except branches show their offset
+impl fmt::Display for Insn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Opcode::*;
+        write!(f, "{}", format!("{}", self.op).to_lowercase())?;
+        let (_, used, n) = layout(self.op);
+        for r in [self.a, self.b, self.c][..n as usize].iter() {
+            write!(f, " ${}", r)?;
+        }
+        if used == n {
+            return Ok(());
+        }
+        match self.op {
+            BR | BEQ | BNE => write!(f, " {:+}", self.off() as i16),
+            PRTS | SYSCALL => write!(f, " #{}", self.off()),
+            LOAD | LOADF => write!(f, " #{}", self.imm()),
+            _ => write!(f, " #{}", self.imm() as i16),
+        }
+    }
+}
+
+/// how many bytes an instruction takes, how many of its operand bytes are
+/// read (the rest is padding) and how many of those are registers
+///
+/// Everything the assembler emits is 4 bytes; HLT and IGL stop the VM one
+/// byte in, so they count as 1.
+fn layout(op: Opcode) -> (u8, u8, u8) {
+    use Opcode::*;
+    match op {
+        HLT | IGL => (1, 0, 0),
+        NOP => (4, 0, 0),
+        JMP | JMPB | JMPF => (4, 1, 1),
+        BR | BEQ | BNE | PRTS | SYSCALL => (4, 2, 0),
+        LOAD | LOADF | ADDI | SUBI | MULI | EQI | NEQI | GTI | LTI | GEQI | LEQI => (4, 3, 1),
+        NEG | INC | DEC | NOT | JEQ | JNE | FREE | JZ | JNZ | JS | JNS | JC | JNC | JO | JNO => {
+            (4, 1, 1)
+        }
+        MOV | EQ | NEQ | GT | GEQ | LT | LEQ | EQF | NEQF | GTF | GEQF | LTF | LEQF | ITOF
+        | FTOI | ALOC => (4, 2, 2),
+        ADD | SUB | MUL | DIV | OR | AND | XOR | SHL | SHR | SAR | ROL | ROR | MOD | ADDF
+        | SUBF | MULF | DIVF => (4, 3, 3),
+    }
+}
+
+/// decodes the instruction at `pc`, which must be inside `program`
+pub fn decode(program: &[u8], pc: usize) -> Result<Insn, DecodeError> {
+    let op = Opcode::from(program[pc]);
+    let (len, used, regs) = layout(op);
+    // missing padding at the end of the program is fine
+    let operands = &program[pc + 1..program.len().min(pc + len as usize)];
+    if operands.len() < used as usize {
+        return Err(DecodeError::Truncated);
+    }
+    if let Some(&r) = operands[..regs as usize].iter().find(|&&r| r >= 32) {
+        return Err(DecodeError::BadRegister(r));
+    }
+    let byte = |i: usize| operands.get(i).copied().unwrap_or(0);
+    Ok(Insn {
+        op,
+        a: byte(0),
+        b: byte(1),
+        c: byte(2),
+        len,
+    })
+}
+
+/// the instruction at `pc` in assembly syntax, with the labels branches go
+/// to when there is debug info
+pub fn disassemble(program: &[u8], pc: usize, debug: Option<&DebugInfo>) -> String {
+    let insn = match program.get(pc).map(|_| decode(program, pc)) {
+        Some(Ok(insn)) => insn,
+        _ => return "?".to_string(),
+    };
+    let target = match insn.op.is_relative_branch() {
+        true => debug.and_then(|d| d.label_at(pc.wrapping_add_signed(insn.off() as i16 as isize))),
+        false => None,
+    };
+    match target {
+        Some(label) => format!("{} <{}>", insn, label),
+        None => insn.to_string(),
+    }
+}
+
+/// decodes the instructions of straight line code from `start`; entries for
+/// other offsets, and for instructions that don't decode, are left empty
+pub fn predecode(program: &[u8], start: usize) -> Vec<Option<Insn>> {
+    let mut cache = vec![None; program.len()];
+    for pc in (start..program.len()).step_by(4) {
+        cache[pc] = decode(program, pc).ok();
+    }
+    cache
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_decode() {
+        let program = [Opcode::ADDI as u8, 3, 0xff, 0xfe, Opcode::HLT as u8];
+        let i = decode(&program, 0).unwrap();
+        assert_eq!((i.op, i.a, i.imm() as i16, i.len), (Opcode::ADDI, 3, -2, 4));
+        assert_eq!(decode(&program, 4).unwrap().len, 1);
+        // the same slots as the assembler, padding or not
+        for op in [Opcode::NOP, Opcode::JMP, Opcode::JMPF, Opcode::BR] {
+            assert_eq!(decode(&[op as u8, 1, 0, 4], 0).unwrap().len, 4);
+            assert_eq!(decode(&[op as u8, 1, 0], 0).unwrap().len, 4);
+        }
+        assert_eq!(
+            decode(&[Opcode::ADD as u8, 1, 32, 0], 0),
+            Err(DecodeError::BadRegister(32))
+        );
+        assert_eq!(
+            decode(&[Opcode::LOAD as u8, 1], 0),
+            Err(DecodeError::Truncated)
+        );
+        assert!(decode(&[Opcode::EQ as u8, 1, 2], 0).is_ok());
+        let cache = predecode(&program, 0);
+        assert_eq!(cache[0], Some(i));
+        assert_eq!(cache[1], None);
+        assert_eq!(cache[4], Some(decode(&program, 4).unwrap()));
+        assert_eq!(predecode(&[Opcode::LOAD as u8, 1], 0), vec![None, None]);
+    }
+    #[test]
+    fn test_disassemble() {
+        let insn = |bytes: &[u8]| decode(bytes, 0).unwrap();
+        let addi = insn(&[Opcode::ADDI as u8, 3, 0xff, 0xfe]);
+        assert_eq!(addi.to_string(), "addi $3 #-2");
+        assert_eq!(addi.reads(), vec![Reg::Int(3)]);
+        let add = insn(&[Opcode::ADD as u8, 1, 2, 3]);
+        assert_eq!(add.to_string(), "add $1 $2 $3");
+        assert_eq!(add.reads(), vec![Reg::Int(1), Reg::Int(2)]);
+        let itof = insn(&[Opcode::ITOF as u8, 1, 2, 0]);
+        assert_eq!(itof.regs(), vec![Reg::Int(1), Reg::Float(2)]);
+        assert_eq!(itof.reads(), vec![Reg::Int(1)]);
+        assert_eq!(itof.writes(), vec![Reg::Float(2)]);
+        assert_eq!(addi.writes(), vec![Reg::Int(3)]);
+        assert_eq!(add.writes(), vec![Reg::Int(3)]);
+        assert_eq!(
+            insn(&[Opcode::BNE as u8, 0xff, 0xfc, 0]).to_string(),
+            "bne -4"
+        );
+        assert_eq!(
+            insn(&[Opcode::LOAD as u8, 0, 0xff, 0xff]).to_string(),
+            "load $0 #65535"
+        );
+        assert_eq!(insn(&[Opcode::HLT as u8]).to_string(), "hlt");
+        let debug = DebugInfo {
+            symbols: vec![("top".to_string(), crate::asm::SymbolType::Label, 0)],
+            ..DebugInfo::default()
+        };
+        let program = [Opcode::NOP as u8, 0, 0, 0, Opcode::BNE as u8, 0xff, 0xfc, 0];
+        assert_eq!(disassemble(&program, 4, None), "bne -4");
+        assert_eq!(disassemble(&program, 4, Some(&debug)), "bne -4 <top>");
+        assert_eq!(disassemble(&program, 8, Some(&debug)), "?");
+    }
+}
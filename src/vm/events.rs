@@ -0,0 +1,150 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::io;
+
+use super::debug::Watch;
+use super::VMFault;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VMEventType {
+    Start,
+    Stop,
+    Fault(VMFault),
+    PolicyViolation(VMFault),
+    OutOfFuel,
+    TimedOut,
+    Interrupted,
+    Syscall(usize, u16),                                // pc, id
+    HeapGrowth(usize, usize, usize),                    // pc, old size, new size
+    Breakpoint(usize),                                  // pc
+    Watchpoint(usize, Watch, Option<i32>, Option<i32>), // pc, what, old value, new value
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VMEvent {
+    pub event: VMEventType,
+    pub at: DateTime<Utc>,
+    pub vm_id: uuid::Uuid,
+}
+
+impl VMEventType {
+    /// snake case name, the `event` field of the JSON form
+    pub fn name(&self) -> &'static str {
+        match self {
+            VMEventType::Start => "start",
+            VMEventType::Stop => "stop",
+            VMEventType::Fault(_) => "fault",
+            VMEventType::PolicyViolation(_) => "policy_violation",
+            VMEventType::OutOfFuel => "out_of_fuel",
+            VMEventType::TimedOut => "timed_out",
+            VMEventType::Interrupted => "interrupted",
+            VMEventType::Syscall(..) => "syscall",
+            VMEventType::HeapGrowth(..) => "heap_growth",
+            VMEventType::Breakpoint(_) => "breakpoint",
+            VMEventType::Watchpoint(..) => "watchpoint",
+        }
+    }
+}
+
+impl VMEvent {
+    /// the event as one line of JSON, for monitoring to ingest
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"vm_id\":\"{}\",\"at\":\"{}\",\"event\":\"{}\"",
+            self.vm_id,
+            self.at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.event.name()
+        );
+        match &self.event {
+            VMEventType::Fault(f) | VMEventType::PolicyViolation(f) => json.push_str(&format!(
+                ",\"pc\":{},\"reason\":{}",
+                f.pc(),
+                json_string(&f.to_string())
+            )),
+            VMEventType::Syscall(pc, id) => json.push_str(&format!(",\"pc\":{},\"id\":{}", pc, id)),
+            VMEventType::HeapGrowth(pc, from, to) => {
+                json.push_str(&format!(",\"pc\":{},\"from\":{},\"to\":{}", pc, from, to))
+            }
+            VMEventType::Breakpoint(pc) => json.push_str(&format!(",\"pc\":{}", pc)),
+            VMEventType::Watchpoint(pc, w, old, new) => {
+                let value = |v: &Option<i32>| v.map_or("null".to_string(), |v| v.to_string());
+                json.push_str(&format!(
+                    ",\"pc\":{},\"watch\":\"{}\",\"old\":{},\"new\":{}",
+                    pc,
+                    w,
+                    value(old),
+                    value(new)
+                ))
+            }
+            _ => {}
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// writes `events` as JSON lines
+pub fn write_json_lines<'a, W: io::Write>(
+    w: &mut W,
+    events: impl IntoIterator<Item = &'a VMEvent>,
+) -> io::Result<()> {
+    for e in events {
+        writeln!(w, "{}", e.to_json())?;
+    }
+    Ok(())
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_event_json() {
+        let at = DateTime::<Utc>::from_timestamp(0, 0).unwrap();
+        let vm_id = uuid::Uuid::nil();
+        let event = |event| VMEvent { event, at, vm_id };
+        let prefix = "{\"vm_id\":\"00000000-0000-0000-0000-000000000000\",\"at\":\"1970-01-01T00:00:00.000000Z\"";
+        assert_eq!(
+            event(VMEventType::Start).to_json(),
+            format!("{},\"event\":\"start\"}}", prefix)
+        );
+        assert_eq!(
+            event(VMEventType::Fault(VMFault::Syscall(
+                8,
+                1,
+                "say \"hi\"".into()
+            )))
+            .to_json(),
+            format!(
+                "{},\"event\":\"fault\",\"pc\":8,\"reason\":\"syscall 1 failed: say \\\"hi\\\"\"}}",
+                prefix
+            )
+        );
+        let mut out = vec![];
+        write_json_lines(
+            &mut out,
+            &[
+                event(VMEventType::HeapGrowth(4, 0, 16)),
+                event(VMEventType::Stop),
+            ],
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 2);
+        assert!(out.starts_with(prefix));
+        assert!(out.contains("\"event\":\"heap_growth\",\"pc\":4,\"from\":0,\"to\":16}\n"));
+    }
+}
@@ -0,0 +1,75 @@
+/// Status flags set by the integer ALU opcodes. Comparisons keep using
+/// `VM::bool_flag`. The layout is fixed for the JIT, which writes the fields
+/// one byte each in this order.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct Flags {
+    pub zero: bool,
+    pub negative: bool,
+    pub carry: bool,    // unsigned overflow, or borrow for subtractions
+    pub overflow: bool, // signed overflow
+}
+
+impl Flags {
+    /// flags for `result` with the given carry and overflow
+    pub fn of(result: i32, carry: bool, overflow: bool) -> Flags {
+        Flags {
+            zero: result == 0,
+            negative: result < 0,
+            carry,
+            overflow,
+        }
+    }
+
+    pub fn add(a: i32, b: i32) -> (i32, Flags) {
+        let (r, overflow) = a.overflowing_add(b);
+        let carry = (a as u32).overflowing_add(b as u32).1;
+        (r, Flags::of(r, carry, overflow))
+    }
+
+    pub fn sub(a: i32, b: i32) -> (i32, Flags) {
+        let (r, overflow) = a.overflowing_sub(b);
+        let carry = (a as u32) < (b as u32);
+        (r, Flags::of(r, carry, overflow))
+    }
+
+    /// carry and overflow both mean the signed product didn't fit
+    pub fn mul(a: i32, b: i32) -> (i32, Flags) {
+        let (r, overflow) = a.overflowing_mul(b);
+        (r, Flags::of(r, overflow, overflow))
+    }
+
+    /// flags of a bitwise or shift result, carry and overflow are cleared
+    pub fn logic(r: i32) -> (i32, Flags) {
+        (r, Flags::of(r, false, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_flags_add() {
+        assert_eq!(Flags::add(1, 2), (3, Flags::default()));
+        let (r, f) = Flags::add(i32::MAX, 1);
+        assert_eq!(r, i32::MIN);
+        assert!(f.overflow && f.negative && !f.carry && !f.zero);
+        let (r, f) = Flags::add(-1, 1);
+        assert_eq!(r, 0);
+        assert!(f.carry && f.zero && !f.overflow);
+    }
+    #[test]
+    fn test_flags_sub() {
+        let (r, f) = Flags::sub(1, 2);
+        assert_eq!(r, -1);
+        assert!(f.carry && f.negative && !f.overflow);
+        let (_, f) = Flags::sub(i32::MIN, 1);
+        assert!(f.overflow && !f.carry);
+    }
+    #[test]
+    fn test_flags_mul() {
+        let (_, f) = Flags::mul(1 << 16, 1 << 16);
+        assert!(f.overflow && f.carry && f.zero);
+        assert_eq!(Flags::mul(-3, 3).1, Flags::of(-9, false, false));
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::VecDeque;
+
+use super::alloc::Journal;
+use super::debug::Watch;
+use super::decode::{Insn, Reg};
+use super::flags::Flags;
+use super::{VMFault, VM};
+use crate::instruction::Opcode;
+
+/// heap bytes the recorded steps may keep before the oldest are dropped
+pub const HISTORY_BYTES: usize = 16 * 1024 * 1024;
+
+/// What one step wrote, with the old values, enough to undo it
+#[derive(Clone, Debug)]
+pub struct Delta {
+    pub step: u64,               // number of the step since recording started
+    pub pc: usize,               // of the instruction
+    pub regs: Vec<(usize, i32)>, // register, old value
+    pub fregs: Vec<(usize, f64)>,
+    pub remainder: u32,
+    pub bool_flag: bool,
+    pub flags: Flags,
+    pub heap: Vec<(usize, Vec<u8>)>, // address, old bytes; in the order written
+    pub heap_len: usize,
+    pub fault: Option<VMFault>,
+    allocator: Option<Journal>, // only for instructions that allocate or free
+}
+
+/// The last `capacity` steps, recorded by `VM::step` while the VM has one,
+/// fewer if their heap bytes add up to more than `max_bytes`
+#[derive(Clone, Debug)]
+pub struct History {
+    pub capacity: usize,
+    pub max_bytes: usize,
+    deltas: VecDeque<Delta>,
+    steps: u64,
+    bytes: usize,                              // heap bytes kept by `deltas`
+    pub(super) written: Vec<(usize, Vec<u8>)>, // by the host function of the step
+}
+
+/// The state before a step, compared with the state after it
+pub(super) struct Before {
+    pc: usize,
+    insn: Insn,
+    regs: [i32; 32],
+    fregs: [f64; 32],
+    remainder: u32,
+    bool_flag: bool,
+    flags: Flags,
+    fault: Option<VMFault>,
+    heap_len: usize,
+}
+
+impl Delta {
+    fn bytes(&self) -> usize {
+        let journal = self.allocator.iter().flat_map(|j| &j.heap);
+        self.heap.iter().chain(journal).map(|(_, b)| b.len()).sum()
+    }
+
+    /// whether the step wrote `w`, even with the value it had
+    pub fn writes(&self, w: Watch) -> bool {
+        let journal = self.allocator.iter().flat_map(|j| &j.heap);
+        match w {
+            Watch::Reg(r) => self.regs.iter().any(|&(i, _)| i == r),
+            Watch::Heap(addr) => self
+                .heap
+                .iter()
+                .chain(journal)
+                .any(|(a, b)| (*a..a + b.len()).contains(&addr)),
+        }
+    }
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            max_bytes: HISTORY_BYTES,
+            deltas: VecDeque::new(),
+            steps: 0,
+            bytes: 0,
+            written: vec![],
+        }
+    }
+
+    /// recorded steps, oldest first
+    pub fn deltas(&self) -> &VecDeque<Delta> {
+        &self.deltas
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+        self.steps = 0;
+        self.bytes = 0;
+    }
+
+    fn push(&mut self, mut delta: Delta) {
+        if self.capacity == 0 {
+            return;
+        }
+        delta.step = self.steps;
+        self.steps += 1;
+        self.bytes += delta.bytes();
+        self.deltas.push_back(delta);
+        while self.deltas.len() > self.capacity
+            || self.bytes > self.max_bytes && self.deltas.len() > 1
+        {
+            let oldest = self.deltas.pop_front().unwrap();
+            self.bytes -= oldest.bytes();
+        }
+    }
+
+    fn pop(&mut self) -> Option<Delta> {
+        let delta = self.deltas.pop_back()?;
+        self.steps = delta.step;
+        self.bytes -= delta.bytes();
+        Some(delta)
+    }
+
+    /// the last recorded step that wrote `w`
+    pub fn last_write(&self, w: Watch) -> Option<&Delta> {
+        self.deltas.iter().rev().find(|d| d.writes(w))
+    }
+}
+
+impl VM {
+    /// starts recording the last `capacity` steps, so they can be undone
+    pub fn record(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.history = None;
+    }
+
+    pub(super) fn before_step(&mut self, pc: usize, insn: Insn) -> Before {
+        if matches!(insn.op, Opcode::ALOC | Opcode::FREE) {
+            self.allocator.journal = Some(Journal::default());
+        }
+        Before {
+            pc,
+            insn,
+            regs: self.regs,
+            fregs: self.fregs,
+            remainder: self.remainder,
+            bool_flag: self.bool_flag,
+            flags: self.flags,
+            fault: self.fault.clone(),
+            heap_len: self.heap.len(),
+        }
+    }
+
+    pub(super) fn after_step(&mut self, before: Before) {
+        // a faulting instruction doesn't get to write its registers
+        let writes = match self.fault.is_some() && before.fault.is_none() {
+            true => vec![],
+            false => before.insn.writes(),
+        };
+        let regs = (0..32)
+            .filter(|&i| self.regs[i] != before.regs[i] || writes.contains(&Reg::Int(i)))
+            .map(|i| (i, before.regs[i]))
+            .collect();
+        let fregs = (0..32)
+            .filter(|&i| {
+                self.fregs[i].to_bits() != before.fregs[i].to_bits()
+                    || writes.contains(&Reg::Float(i))
+            })
+            .map(|i| (i, before.fregs[i]))
+            .collect();
+        let heap = self
+            .history
+            .as_mut()
+            .map(|h| std::mem::take(&mut h.written))
+            .unwrap_or_default();
+        let allocator = self.allocator.journal.take();
+        let delta = Delta {
+            step: 0,
+            pc: before.pc,
+            regs,
+            fregs,
+            remainder: before.remainder,
+            bool_flag: before.bool_flag,
+            flags: before.flags,
+            heap,
+            heap_len: before.heap_len,
+            fault: before.fault,
+            allocator,
+        };
+        if let Some(history) = self.history.as_mut() {
+            history.push(delta);
+        }
+    }
+
+    /// undoes the last recorded step, false if there is none
+    ///
+    /// fuel and events aren't given back
+    pub fn reverse_step(&mut self) -> bool {
+        let delta = match self.history.as_mut().and_then(|h| h.pop()) {
+            Some(delta) => delta,
+            None => return false,
+        };
+        self.pc = delta.pc;
+        for (i, v) in delta.regs {
+            self.regs[i] = v;
+        }
+        for (i, v) in delta.fregs {
+            self.fregs[i] = v;
+        }
+        self.remainder = delta.remainder;
+        self.bool_flag = delta.bool_flag;
+        self.flags = delta.flags;
+        self.fault = delta.fault;
+        self.heap.resize(delta.heap_len, 0);
+        let journal = delta.allocator.iter().flat_map(|j| &j.heap);
+        // the oldest bytes go back last
+        for (addr, bytes) in delta.heap.iter().chain(journal).rev() {
+            if let Some(old) = self.heap.get_mut(*addr..addr + bytes.len()) {
+                old.copy_from_slice(bytes);
+            }
+        }
+        if let Some(journal) = delta.allocator {
+            self.allocator.undo(journal);
+        }
+        // going forward again runs the instruction here, even on a
+        // breakpoint, and watchpoints compare with the values now
+        self.debugger.resume_at = Some(self.pc);
+        self.sync_watchpoints();
+        true
+    }
+
+    /// undoes steps until the VM is back on a breakpoint, false if the
+    /// recording runs out first
+    pub fn reverse_continue(&mut self) -> bool {
+        while self.reverse_step() {
+            if self.debugger.has_breakpoint(self.pc) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::run;
+    use crate::vm::VMExit;
+    #[test]
+    fn test_reverse() {
+        let mut vm = VM::new();
+        // 0: load $0 #3; 4: load $2 #12; 8: aloc $0 $1; 12: dec $0; 16: jnz $2; 20: hlt
+        vm.set_program(vec![
+            Opcode::LOAD as u8,
+            0,
+            0,
+            3,
+            Opcode::LOAD as u8,
+            2,
+            0,
+            12,
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+            Opcode::DEC as u8,
+            0,
+            0,
+            0,
+            Opcode::JNZ as u8,
+            2,
+            0,
+            0,
+            Opcode::HLT as u8,
+        ]);
+        vm.record(100);
+        vm.add_breakpoint(12);
+        assert_eq!(run(&mut vm), VMExit::Paused);
+        assert_eq!(run(&mut vm), VMExit::Paused);
+        assert_eq!((vm.pc, vm.regs[0]), (12, 2));
+        let heap_len = vm.heap.len();
+        assert!(heap_len > 0);
+
+        // back over the jump and the dec
+        assert!(vm.reverse_step());
+        assert_eq!((vm.pc, vm.regs[0]), (16, 2));
+        assert!(vm.reverse_step());
+        assert_eq!((vm.pc, vm.regs[0]), (12, 3));
+        let history = vm.history.as_ref().unwrap();
+        assert_eq!(history.last_write(Watch::Reg(0)).unwrap().pc, 0);
+        assert_eq!(history.last_write(Watch::Reg(2)).unwrap().pc, 4);
+        // aloc wrote address 0 over the 0 in $1
+        assert_eq!(history.last_write(Watch::Reg(1)).unwrap().pc, 8);
+        assert!(history.last_write(Watch::Reg(3)).is_none());
+        // we're on the breakpoint, so go back to the one before: there is none
+        assert!(!vm.reverse_continue());
+        assert_eq!((vm.pc, vm.regs[0], vm.heap.len()), (0, 0, 0));
+
+        // the allocator was undone too, so running again gives the same heap
+        assert_eq!(run(&mut vm), VMExit::Paused);
+        assert_eq!(vm.heap.len(), heap_len);
+        assert_eq!(run(&mut vm), VMExit::Paused);
+        assert!(vm.reverse_continue());
+        assert_eq!((vm.pc, vm.regs[0]), (12, 3));
+        vm.debugger.delete(1);
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        assert_eq!(vm.regs[0], 0);
+    }
+    #[test]
+    fn test_history_is_bounded() {
+        let mut vm = VM::new();
+        vm.set_program([Opcode::INC as u8, 0, 0, 0].repeat(10));
+        vm.record(4);
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        let history = vm.history.as_ref().unwrap();
+        assert_eq!(history.deltas().len(), 4);
+        assert_eq!(history.deltas()[0].step, 6);
+        while vm.reverse_step() {}
+        assert_eq!((vm.pc, vm.regs[0]), (24, 6));
+    }
+    #[test]
+    fn test_reverse_heap() {
+        let mut vm = VM::new();
+        vm.set_heap_debug(true);
+        vm.set_heap_quarantine(0);
+        vm.regs[0] = 8;
+        // writes the value heap[2] already has
+        vm.register_syscall(1, "same", |ctx| {
+            let old = ctx.heap()[2];
+            ctx.heap_mut(2..3).unwrap()[0] = old;
+            Ok(0)
+        });
+        // 0: aloc $0 $1; 4: free $1; 8: aloc $0 $1; 12: syscall 1; 16: load $2 #0
+        vm.set_program(vec![
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+            Opcode::FREE as u8,
+            1,
+            0,
+            0,
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+            Opcode::SYSCALL as u8,
+            0,
+            1,
+            0,
+            Opcode::LOAD as u8,
+            2,
+            0,
+            0,
+        ]);
+        vm.record(10);
+        let mut states = vec![];
+        while vm.pc < vm.program.len() {
+            states.push((vm.heap.clone(), vm.heap_stats(), vm.regs));
+            vm.step();
+        }
+        assert_eq!(vm.fault, None);
+        let history = vm.history.as_ref().unwrap();
+        // writes of the value already there count
+        assert_eq!(history.last_write(Watch::Reg(2)).unwrap().pc, 16);
+        assert_eq!(history.last_write(Watch::Heap(2)).unwrap().pc, 12);
+        // only the block's bytes were kept, not the whole heap
+        assert!(history.deltas().iter().all(|d| d.bytes() <= 8));
+        while let Some(state) = states.pop() {
+            assert!(vm.reverse_step());
+            assert_eq!((vm.heap.clone(), vm.heap_stats(), vm.regs), state);
+        }
+        // the allocator is back too: the same steps give the same heap
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        assert_eq!(vm.heap_stats().live_blocks, 1);
+    }
+    #[test]
+    fn test_history_bytes_are_bounded() {
+        let mut vm = VM::new();
+        vm.set_heap_debug(false);
+        vm.regs[0] = 64;
+        // aloc $0 $1; free $1, over and over: each reuse keeps 64 bytes
+        vm.set_program([Opcode::ALOC as u8, 0, 1, 0, Opcode::FREE as u8, 1, 0, 0].repeat(8));
+        vm.record(100);
+        vm.history.as_mut().unwrap().max_bytes = 128;
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        let history = vm.history.as_ref().unwrap();
+        assert!(history.deltas().iter().map(|d| d.bytes()).sum::<usize>() <= 128);
+        assert!(history.deltas().len() < 16);
+    }
+}
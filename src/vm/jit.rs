@@ -0,0 +1,452 @@
+//! Template JIT: straight line runs of bytecode ending at a branch are
+//! translated to x86-64, one fixed instruction sequence per opcode. Blocks
+//! start wherever `run` finds itself and stop before the first opcode the
+//! JIT doesn't know, which the interpreter then runs.
+
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("the jit feature needs x86-64");
+
+use std::sync::Arc;
+
+use memmap2::{Mmap, MmapMut};
+
+use super::decode::decode;
+use super::flags::Flags;
+use crate::instruction::Opcode;
+
+/// regs, fregs, bool_flag, flags; returns the pc to continue from
+type BlockFn = unsafe extern "sysv64" fn(*mut i32, *mut f64, *mut bool, *mut Flags) -> u64;
+
+/// A compiled basic block
+pub struct Block {
+    code: Mmap,
+    source: Vec<u8>, // the bytecode it was compiled from
+    pub steps: u64,  // instructions it runs
+}
+
+/// Compiled blocks by the pc they start at
+#[derive(Clone, Default)]
+pub struct Jit {
+    blocks: Vec<Slot>,
+}
+
+#[derive(Clone, Default)]
+enum Slot {
+    #[default]
+    Untried,
+    Failed, // nothing could be compiled there
+    Compiled(Arc<Block>),
+}
+
+impl Jit {
+    /// the block starting at `pc`, compiling it the first time
+    pub fn block(&mut self, program: &[u8], pc: usize) -> Option<&Block> {
+        if self.blocks.len() != program.len() {
+            self.blocks = vec![Slot::Untried; program.len()];
+        }
+        if pc >= program.len() {
+            return None;
+        }
+        let stale = match &self.blocks[pc] {
+            Slot::Failed => return None,
+            // the program may have changed under the block
+            Slot::Compiled(b) => program.get(pc..pc + b.source.len()) != Some(&b.source),
+            Slot::Untried => true,
+        };
+        if stale {
+            self.blocks[pc] = match compile(program, pc) {
+                Some(b) => Slot::Compiled(Arc::new(b)),
+                None => Slot::Failed,
+            };
+        }
+        match &self.blocks[pc] {
+            Slot::Compiled(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl Block {
+    pub fn run(
+        &self,
+        regs: &mut [i32; 32],
+        fregs: &mut [f64; 32],
+        bool_flag: &mut bool,
+        flags: &mut Flags,
+    ) -> usize {
+        // SAFETY: the code was generated by `compile` for this signature and
+        // only touches the registers of instructions that passed `decode`
+        unsafe {
+            let f: BlockFn = std::mem::transmute(self.code.as_ptr());
+            f(regs.as_mut_ptr(), fregs.as_mut_ptr(), bool_flag, flags) as usize
+        }
+    }
+}
+
+/// compiles the instructions from `start` up to the first branch or
+/// unsupported opcode, None if there are none or the code can't be mapped
+fn compile(program: &[u8], start: usize) -> Option<Block> {
+    let mut e = Emitter::default();
+    // free rcx for shift counts
+    e.bytes(&[0x49, 0x89, 0xc8]); // mov r8, rcx
+    let mut pc = start;
+    let mut steps = 0;
+    loop {
+        if pc >= program.len() {
+            break;
+        }
+        let i = match decode(program, pc) {
+            Ok(i) if pc + i.len as usize <= program.len() => i,
+            _ => break,
+        };
+        let (a, b, c) = (i.a, i.b, i.c);
+        let imm = i.imm() as i16 as i32;
+        match i.op {
+            Opcode::LOAD => e.store_imm(a, i.imm() as i32),
+            Opcode::MOV => {
+                e.load(EAX, b);
+                e.store(a, EAX);
+            }
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHL
+            | Opcode::SHR
+            | Opcode::SAR
+            | Opcode::ROL
+            | Opcode::ROR => {
+                e.load(EAX, a);
+                e.load(ECX, b);
+                e.bytes(match i.op {
+                    Opcode::ADD => &[0x01, 0xc8],       // add eax, ecx
+                    Opcode::SUB => &[0x29, 0xc8],       // sub eax, ecx
+                    Opcode::MUL => &[0x0f, 0xaf, 0xc1], // imul eax, ecx
+                    Opcode::AND => &[0x21, 0xc8],       // and eax, ecx
+                    Opcode::OR => &[0x09, 0xc8],        // or eax, ecx
+                    Opcode::XOR => &[0x31, 0xc8],       // xor eax, ecx
+                    Opcode::SHL => &[0xd3, 0xe0],       // shl eax, cl
+                    Opcode::SHR => &[0xd3, 0xe8],       // shr eax, cl
+                    Opcode::SAR => &[0xd3, 0xf8],       // sar eax, cl
+                    Opcode::ROL => &[0xd3, 0xc0],       // rol eax, cl
+                    _ => &[0xd3, 0xc8],                 // ror eax, cl
+                });
+                e.flags(i.op);
+                e.store(c, EAX);
+            }
+            Opcode::NEG | Opcode::NOT | Opcode::INC | Opcode::DEC => {
+                e.load(EAX, a);
+                match i.op {
+                    Opcode::NEG => e.bytes(&[0xf7, 0xd8]), // neg eax
+                    Opcode::NOT => e.bytes(&[0xf7, 0xd0]), // not eax
+                    // not inc and dec, which leave the carry alone
+                    Opcode::INC => e.op_imm(0x05, 1), // add eax, 1
+                    _ => e.op_imm(0x2d, 1),           // sub eax, 1
+                }
+                e.flags(i.op);
+                e.store(a, EAX);
+            }
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => {
+                e.load(EAX, a);
+                match i.op {
+                    Opcode::ADDI => e.op_imm(0x05, imm), // add eax, imm
+                    Opcode::SUBI => e.op_imm(0x2d, imm), // sub eax, imm
+                    _ => {
+                        e.bytes(&[0x69, 0xc0]); // imul eax, eax, imm
+                        e.imm32(imm);
+                    }
+                }
+                e.flags(i.op);
+                e.store(a, EAX);
+            }
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::GEQ | Opcode::LT | Opcode::LEQ => {
+                e.load(EAX, a);
+                e.load(ECX, b);
+                e.bytes(&[0x39, 0xc8]); // cmp eax, ecx
+                e.set_bool(i.op);
+            }
+            Opcode::EQI
+            | Opcode::NEQI
+            | Opcode::GTI
+            | Opcode::GEQI
+            | Opcode::LTI
+            | Opcode::LEQI => {
+                e.load(EAX, a);
+                e.op_imm(0x3d, imm); // cmp eax, imm
+                e.set_bool(i.op);
+            }
+            Opcode::ADDF | Opcode::SUBF | Opcode::MULF | Opcode::DIVF => {
+                e.sse(0x10, a); // movsd xmm0, a
+                let op = match i.op {
+                    Opcode::ADDF => 0x58,
+                    Opcode::SUBF => 0x5c,
+                    Opcode::MULF => 0x59,
+                    _ => 0x5e,
+                };
+                e.sse(op, b); // op xmm0, b
+                e.sse(0x11, c); // movsd c, xmm0
+            }
+            Opcode::BR | Opcode::BEQ | Opcode::BNE => {
+                let target = pc.wrapping_add_signed(i.off() as i16 as isize);
+                let next = pc + i.len as usize;
+                match i.op {
+                    Opcode::BR => e.ret(target),
+                    op => e.ret_if(op == Opcode::BEQ, target, next),
+                }
+                return e.finish(program[start..next].to_vec(), steps + 1);
+            }
+            _ => break,
+        }
+        steps += 1;
+        pc += i.len as usize;
+    }
+    if steps == 0 {
+        return None;
+    }
+    e.ret(pc);
+    e.finish(program[start..pc].to_vec(), steps)
+}
+
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+
+#[derive(Default)]
+struct Emitter {
+    code: Vec<u8>,
+}
+
+impl Emitter {
+    fn bytes(&mut self, b: &[u8]) {
+        self.code.extend_from_slice(b);
+    }
+
+    fn imm32(&mut self, i: i32) {
+        self.bytes(&i.to_le_bytes());
+    }
+
+    /// `op eax, imm32` for the one byte forms of add, sub and cmp
+    fn op_imm(&mut self, op: u8, i: i32) {
+        self.bytes(&[op]);
+        self.imm32(i);
+    }
+
+    /// mov dst, [rdi + 4 * reg]
+    fn load(&mut self, dst: u8, reg: u8) {
+        self.bytes(&[0x8b, 0x87 | dst << 3]);
+        self.imm32(reg as i32 * 4);
+    }
+
+    /// mov [rdi + 4 * reg], src
+    fn store(&mut self, reg: u8, src: u8) {
+        self.bytes(&[0x89, 0x87 | src << 3]);
+        self.imm32(reg as i32 * 4);
+    }
+
+    /// mov dword [rdi + 4 * reg], imm32
+    fn store_imm(&mut self, reg: u8, i: i32) {
+        self.bytes(&[0xc7, 0x87]);
+        self.imm32(reg as i32 * 4);
+        self.imm32(i);
+    }
+
+    /// `op xmm0, [rsi + 8 * freg]`, or the store for movsd's 0x11
+    fn sse(&mut self, op: u8, freg: u8) {
+        self.bytes(&[0xf2, 0x0f, op, 0x86]);
+        self.imm32(freg as i32 * 8);
+    }
+
+    /// writes the x86 flags of the last operation on eax to `Flags`, the
+    /// same way the interpreter computes them
+    fn flags(&mut self, op: Opcode) {
+        let setcc = |e: &mut Emitter, cc: u8, field: u8| e.bytes(&[0x41, 0x0f, cc, 0x40, field]);
+        match op {
+            Opcode::ADD | Opcode::SUB | Opcode::NEG | Opcode::INC | Opcode::DEC => {}
+            Opcode::ADDI | Opcode::SUBI => {}
+            // imul leaves zero and sign undefined
+            Opcode::MUL | Opcode::MULI => {
+                setcc(self, 0x90, 2); // seto carry
+                setcc(self, 0x90, 3); // seto overflow
+                self.bytes(&[0x85, 0xc0]); // test eax, eax
+                setcc(self, 0x94, 0); // setz zero
+                setcc(self, 0x98, 1); // sets negative
+                return;
+            }
+            // shifts by 0 and not don't touch the flags, test also clears carry and overflow
+            _ => self.bytes(&[0x85, 0xc0]), // test eax, eax
+        }
+        setcc(self, 0x94, 0); // setz zero
+        setcc(self, 0x98, 1); // sets negative
+        setcc(self, 0x92, 2); // setc carry
+        setcc(self, 0x90, 3); // seto overflow
+    }
+
+    /// setcc [rdx] after a signed cmp
+    fn set_bool(&mut self, op: Opcode) {
+        let cc = match op {
+            Opcode::EQ | Opcode::EQI => 0x94,
+            Opcode::NEQ | Opcode::NEQI => 0x95,
+            Opcode::GT | Opcode::GTI => 0x9f,
+            Opcode::GEQ | Opcode::GEQI => 0x9d,
+            Opcode::LT | Opcode::LTI => 0x9c,
+            _ => 0x9e,
+        };
+        self.bytes(&[0x0f, cc, 0x02]);
+    }
+
+    fn ret(&mut self, pc: usize) {
+        self.bytes(&[0x48, 0xb8]); // mov rax, pc
+        self.bytes(&(pc as u64).to_le_bytes());
+        self.bytes(&[0xc3]);
+    }
+
+    /// returns `target` if bool_flag is `when`, else `next`
+    fn ret_if(&mut self, when: bool, target: usize, next: usize) {
+        self.bytes(&[0x0f, 0xb6, 0x0a]); // movzx ecx, byte [rdx]
+        self.bytes(&[0x48, 0xb8]); // mov rax, target
+        self.bytes(&(target as u64).to_le_bytes());
+        self.bytes(&[0x49, 0xb9]); // mov r9, next
+        self.bytes(&(next as u64).to_le_bytes());
+        self.bytes(&[0x85, 0xc9]); // test ecx, ecx
+                                   // cmovz or cmovnz rax, r9
+        self.bytes(&[0x49, 0x0f, if when { 0x44 } else { 0x45 }, 0xc1]);
+        self.bytes(&[0xc3]);
+    }
+
+    fn finish(self, source: Vec<u8>, steps: u64) -> Option<Block> {
+        let mut map = MmapMut::map_anon(self.code.len()).ok()?;
+        map.copy_from_slice(&self.code);
+        Some(Block {
+            code: map.make_exec().ok()?,
+            source,
+            steps,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use crate::vm::VM;
+
+    /// the state a program can observe, compared between the two modes
+    fn state(vm: &VM) -> String {
+        let fregs: Vec<u64> = vm.fregs.iter().map(|f| f.to_bits()).collect();
+        format!(
+            "{:?} {:?} {} {} {:?} {:?} {:?} {:?} {:?} {:?}",
+            vm.regs,
+            fregs,
+            vm.pc,
+            vm.bool_flag,
+            vm.flags,
+            vm.heap,
+            vm.fault,
+            vm.fuel,
+            vm.remainder,
+            vm.output.as_deref().map(String::from_utf8_lossy)
+        )
+    }
+
+    /// runs copies of `vm` with and without the jit and checks they end the
+    /// same, output included; the VM and assembler tests run their programs
+    /// through here too
+    pub fn differential(vm: &VM) {
+        let mut vm = vm.clone();
+        // they only watch, but would keep the jit from running
+        (vm.history, vm.tracer, vm.profile, vm.coverage) = (None, None, None, None);
+        let mut interpreted = vm.clone();
+        interpreted.jit = false;
+        interpreted.output = Some(vec![]);
+        let mut compiled = vm.clone();
+        compiled.jit = true;
+        compiled.output = Some(vec![]);
+        let exits = (interpreted.run(), compiled.run());
+        assert_eq!(exits.0, exits.1);
+        assert_eq!(state(&interpreted), state(&compiled));
+    }
+
+    fn assembled(src: &str, fuel: u64) -> VM {
+        let mut asm = crate::asm::Assembler::new();
+        let prog = asm.assemble(src).unwrap();
+        let mut vm = VM::new();
+        vm.load(prog, asm.ro).unwrap();
+        vm.fuel = Some(fuel);
+        vm
+    }
+
+    #[test]
+    fn test_jit_programs() {
+        for (_, src) in crate::bench::PROGRAMS {
+            // fuel running out in the middle of a block
+            differential(&assembled(src, 10_007));
+        }
+        differential(&assembled(
+            ".code
+            load $0 #-3
+            load $1 #33
+            shl $0 $1 $2
+            shr $0 $1 $3
+            sar $0 $1 $4
+            rol $0 $1 $5
+            ror $0 $1 $6
+            not $0
+            neg $1
+            load $7 #0
+            dec $7
+            mul $7 $7 $8
+            muli $8 #-2
+            eq $8 $1
+            beq @done
+            gti $7 #-1
+            bne @done
+            hlt
+            done: inc $9
+            hlt",
+            100,
+        ));
+    }
+
+    #[test]
+    fn test_jit_random_programs() {
+        use crate::instruction::Opcode::*;
+        let ops = [
+            LOAD, MOV, ADD, SUB, MUL, AND, OR, XOR, SHL, SHR, SAR, ROL, ROR, NEG, NOT, INC, DEC,
+            ADDI, SUBI, MULI, EQ, NEQ, GT, GEQ, LT, LEQ, EQI, NEQI, GTI, GEQI, LTI, LEQI, ADDF,
+            SUBF, MULF, DIVF,
+        ];
+        let interesting = [0, 1, -1, 2, 31, 32, i32::MAX, i32::MIN, 0x7fff, -0x8000];
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        let mut rand = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for _ in 0..200 {
+            let mut vm = VM::new();
+            for r in 0..8 {
+                vm.regs[r] = match rand() % 3 {
+                    0 => interesting[rand() as usize % interesting.len()],
+                    _ => rand() as i32,
+                };
+                vm.fregs[r] = (rand() % 7) as f64 - 3.0;
+            }
+            for _ in 0..(rand() % 20 + 1) {
+                let op = ops[rand() as usize % ops.len()];
+                let regs = [rand() % 8, rand() % 8, rand() % 8].map(|r| r as u8);
+                vm.program_mut()
+                    .extend([op as u8, regs[0], regs[1], regs[2]]);
+                if matches!(
+                    op,
+                    LOAD | ADDI | SUBI | MULI | EQI | NEQI | GTI | GEQI | LTI | LEQI
+                ) {
+                    // any immediate, not only small registers
+                    let n = vm.program.len();
+                    vm.program[n - 2..].copy_from_slice(&(rand() as u16).to_be_bytes());
+                }
+            }
+            vm.program_mut().extend([HLT as u8, 0, 0, 0]);
+            differential(&vm);
+        }
+    }
+}
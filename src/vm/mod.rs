@@ -0,0 +1,1988 @@
+pub mod alloc;
+pub mod coverage;
+pub mod debug;
+pub mod decode;
+pub mod events;
+pub mod flags;
+pub mod history;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod policy;
+pub mod profile;
+pub mod snapshot;
+pub mod syscall;
+pub mod trace;
+pub mod verify;
+
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use uuid;
+
+use crate::asm::debug::DebugInfo;
+use crate::asm::{PieHeader, PIE_DEBUG_LENGTH_OFFSET};
+use crate::instruction::Opcode;
+
+use self::alloc::{AllocError, Allocator, HeapStats};
+use self::coverage::Coverage;
+use self::debug::{Debugger, Memory, Point, Watch};
+pub use self::events::{VMEvent, VMEventType};
+
+use self::decode::{decode, disassemble, predecode, DecodeError, Insn};
+use self::flags::Flags;
+use self::history::History;
+use self::policy::Policy;
+use self::profile::Profile;
+use self::syscall::{HeapError, SyscallContext, SyscallResult, SyscallTable};
+use self::trace::Tracer;
+use self::verify::{verify, Diagnostic};
+
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+#[derive(Clone)]
+pub struct VM {
+    pub regs: [i32; 32],
+    pub fregs: [f64; 32],
+    pub pc: usize,
+    program: Vec<u8>,
+    pub heap: Vec<u8>,
+    pub remainder: u32,
+    pub bool_flag: bool, // equality flag
+    pub flags: Flags,
+    pub trap_overflow: bool, // fault on signed overflow instead of wrapping
+    pub ro_data: Vec<u8>,
+    pub id: uuid::Uuid,
+    pub fault: Option<VMFault>,
+    pub syscalls: SyscallTable,
+    pub fuel: Option<u64>, // instructions `run` may still execute, None for no limit
+    pub deadline: Option<DateTime<Utc>>,
+    pub interrupt: Arc<AtomicBool>, // set from another thread, e.g. on Ctrl-C, to stop `run`
+    pub policy: Policy,
+    pub decode_cache: bool, // reuse decoded instructions instead of decoding at every step
+    #[cfg(feature = "jit")]
+    pub jit: bool, // run compiled blocks in `run`, unless trapping on overflow or debugging
+    pub debugger: Debugger,
+    pub history: Option<History>, // recorded steps, for reverse debugging
+    pub tracer: Option<Tracer>,
+    pub profile: Option<Profile>,
+    pub coverage: Option<Coverage>,
+    pub debug_info: Option<DebugInfo>, // from the program's debug section
+    pub output: Option<Vec<u8>>,       // what PRTS writes, instead of stdout
+
+    allocator: Allocator,
+    events: Vec<VMEvent>,
+    decoded: Vec<Option<Insn>>, // by pc, see `program_changed`
+    #[cfg(feature = "jit")]
+    jit_blocks: jit::Jit,
+}
+
+/// Why the VM stopped abnormally
+#[derive(Clone, Debug, PartialEq)]
+pub enum VMFault {
+    IllegalOpcode(usize, u8),    // pc, opcode
+    BadRegister(usize, u8),      // pc, register
+    Truncated(usize),            // pc, of an instruction running past the end
+    BadRoOffset(usize, usize),   // pc, offset past the end of ro_data
+    DivisionByZero(usize),       // pc
+    Overflow(usize),             // pc, only with trap_overflow
+    UnknownSyscall(usize, u16),  // pc, id
+    Syscall(usize, u16, String), // pc, id, reason given by the host
+    BadAllocation(usize, i32),   // pc, requested size
+    InvalidFree(usize, i32),     // pc, address
+    DoubleFree(usize, usize),    // pc, address
+    UseAfterFree(usize, usize),  // pc, address of the freed block that was written
+    HeapShrink(usize, usize),    // pc, size a host function asked for, under allocated blocks
+    // policy violations
+    HeapLimit(usize, usize),   // pc, heap size the program asked for
+    SyscallDenied(usize, u16), // pc, id
+    OutputDenied(usize),       // pc
+}
+
+impl VMFault {
+    pub fn is_policy_violation(&self) -> bool {
+        matches!(
+            self,
+            VMFault::HeapLimit(..) | VMFault::SyscallDenied(..) | VMFault::OutputDenied(..)
+        )
+    }
+
+    /// where the faulting instruction starts
+    pub fn pc(&self) -> usize {
+        match *self {
+            VMFault::IllegalOpcode(pc, _)
+            | VMFault::BadRegister(pc, _)
+            | VMFault::Truncated(pc)
+            | VMFault::BadRoOffset(pc, _)
+            | VMFault::DivisionByZero(pc)
+            | VMFault::Overflow(pc)
+            | VMFault::UnknownSyscall(pc, _)
+            | VMFault::Syscall(pc, _, _)
+            | VMFault::BadAllocation(pc, _)
+            | VMFault::InvalidFree(pc, _)
+            | VMFault::DoubleFree(pc, _)
+            | VMFault::UseAfterFree(pc, _)
+            | VMFault::HeapShrink(pc, _)
+            | VMFault::HeapLimit(pc, _)
+            | VMFault::SyscallDenied(pc, _)
+            | VMFault::OutputDenied(pc) => pc,
+        }
+    }
+}
+
+/// the reason, without the pc
+impl fmt::Display for VMFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VMFault::IllegalOpcode(_, op) => write!(f, "illegal opcode {:#04x}", op),
+            VMFault::BadRegister(_, r) => write!(f, "no register {}", r),
+            VMFault::Truncated(_) => write!(f, "truncated instruction"),
+            VMFault::BadRoOffset(_, off) => write!(f, "read-only offset {} out of range", off),
+            VMFault::DivisionByZero(_) => write!(f, "division by zero"),
+            VMFault::Overflow(_) => write!(f, "signed overflow"),
+            VMFault::UnknownSyscall(_, id) => write!(f, "unknown syscall {}", id),
+            VMFault::Syscall(_, id, reason) => write!(f, "syscall {} failed: {}", id, reason),
+            VMFault::BadAllocation(_, size) => write!(f, "bad allocation size {}", size),
+            VMFault::InvalidFree(_, addr) => write!(f, "free of {}, not a block", addr),
+            VMFault::DoubleFree(_, addr) => write!(f, "double free of {}", addr),
+            VMFault::UseAfterFree(_, addr) => {
+                write!(f, "block {} was written after being freed", addr)
+            }
+            VMFault::HeapShrink(_, size) => {
+                write!(f, "heap of {} bytes would cut allocated blocks", size)
+            }
+            VMFault::HeapLimit(_, size) => write!(f, "heap of {} bytes over the limit", size),
+            VMFault::SyscallDenied(_, id) => write!(f, "syscall {} not allowed", id),
+            VMFault::OutputDenied(_) => write!(f, "output not allowed"),
+        }
+    }
+}
+
+/// How `run` returned; OutOfFuel, TimedOut, Interrupted and Paused leave the
+/// VM ready to resume
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VMExit {
+    Halted,
+    Faulted,
+    OutOfFuel,
+    TimedOut,
+    Interrupted, // `interrupt` was set
+    Paused,      // on a breakpoint or watchpoint
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    BadHeader,
+    MissingHostFunction(u16, String), // id, name
+    Invalid(Vec<Diagnostic>),         // the verifier rejected the code
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> VM {
+        VM {
+            regs: [0; 32],
+            fregs: [0.0; 32],
+            pc: 0,
+            program: vec![],
+            heap: vec![],
+            remainder: 0,
+            bool_flag: false,
+            flags: Flags::default(),
+            trap_overflow: false,
+            ro_data: vec![],
+            id: uuid::Uuid::new_v4(),
+            fault: None,
+            syscalls: SyscallTable::new(),
+            fuel: None,
+            deadline: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            policy: Policy::new(),
+            decode_cache: true,
+            #[cfg(feature = "jit")]
+            jit: true,
+            debugger: Debugger::default(),
+            history: None,
+            tracer: None,
+            profile: None,
+            coverage: None,
+            debug_info: None,
+            output: None,
+            allocator: Allocator::new(),
+            events: vec![],
+            decoded: vec![],
+            #[cfg(feature = "jit")]
+            jit_blocks: jit::Jit::default(),
+        }
+    }
+
+    /// makes `f` callable from bytecode with `SYSCALL #id`, or `syscall @name`
+    /// when the program declares `name: .host #id`
+    pub fn register_syscall<F>(&mut self, id: u16, name: &str, f: F)
+    where
+        F: Fn(&mut SyscallContext) -> SyscallResult + Send + Sync + 'static,
+    {
+        self.syscalls.register(id, name, Arc::new(f));
+    }
+
+    /// what happened to the VM so far, oldest first
+    pub fn events(&self) -> &[VMEvent] {
+        &self.events
+    }
+
+    /// removes and returns the events so far
+    pub fn drain_events(&mut self) -> std::vec::Drain<'_, VMEvent> {
+        self.events.drain(..)
+    }
+
+    /// writes the events so far as JSON lines
+    pub fn write_events<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        events::write_json_lines(w, &self.events)
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.allocator.stats(&self.heap)
+    }
+
+    /// enables double free and use after free detection, on by default in debug builds
+    pub fn set_heap_debug(&mut self, debug: bool) {
+        self.allocator.debug = debug;
+    }
+
+    /// how many bytes of freed blocks debug mode keeps from reuse
+    pub fn set_heap_quarantine(&mut self, bytes: usize) {
+        self.allocator.quarantine_bytes = bytes;
+    }
+
+    /// replaces the program with a PIE file and its read-only data, checking
+    /// its host imports and verifying its code first
+    pub fn load(&mut self, mut pie: Vec<u8>, ro_data: Vec<u8>) -> Result<(), LoadError> {
+        let header = PieHeader::parse(&pie).ok_or(LoadError::BadHeader)?;
+        // the debug section isn't code, it's kept in `debug_info` instead
+        pie.truncate(header.code_end);
+        pie[PIE_DEBUG_LENGTH_OFFSET..PIE_DEBUG_LENGTH_OFFSET + 4].fill(0);
+        let code_offset = self.check(&pie, &ro_data)?;
+        self.ro_data = ro_data;
+        self.decoded = predecode(&pie, code_offset);
+        self.program = pie;
+        self.pc = code_offset;
+        self.debug_info = header.debug;
+        Ok(())
+    }
+
+    /// checks the host imports of a program, if it has a PIE header, and
+    /// verifies its code; returns where the code starts
+    fn check(&self, program: &[u8], ro_data: &[u8]) -> Result<usize, LoadError> {
+        let code_offset = match PieHeader::parse(program) {
+            Some(header) => {
+                for import in header.imports {
+                    match self.syscalls.by_id(import.id) {
+                        Some(f) if f.name == import.name => {}
+                        _ => return Err(LoadError::MissingHostFunction(import.id, import.name)),
+                    }
+                }
+                header.code_offset
+            }
+            // bytecode put straight into `program`
+            None => 0,
+        };
+        let diagnostics = verify(program, code_offset, ro_data);
+        if !diagnostics.is_empty() {
+            return Err(LoadError::Invalid(diagnostics));
+        }
+        Ok(code_offset)
+    }
+
+    pub fn run(&mut self) -> VMExit {
+        self.fault = None;
+        self.event(VMEventType::Start);
+        let mut steps: u64 = 0;
+        let mut next_deadline_check = 0;
+        let exit = loop {
+            if self.fuel == Some(0) {
+                break VMExit::OutOfFuel;
+            }
+            // reading the clock is much slower than a step
+            if steps >= next_deadline_check {
+                next_deadline_check = steps + DEADLINE_CHECK_INTERVAL;
+                if self.deadline.is_some_and(|d| Utc::now() >= d) {
+                    break VMExit::TimedOut;
+                }
+                if self.interrupt.swap(false, Ordering::Relaxed) {
+                    break VMExit::Interrupted;
+                }
+            }
+            #[cfg(feature = "jit")]
+            if let Some(n) = self.run_block() {
+                steps += n;
+                continue;
+            }
+            if self.unobserved() {
+                let budget = self
+                    .fuel
+                    .unwrap_or(u64::MAX)
+                    .min(next_deadline_check - steps);
+                let (n, stop) = self.run_decoded(budget);
+                if let Some(fuel) = self.fuel.as_mut() {
+                    *fuel -= n;
+                }
+                steps += n;
+                if stop {
+                    break match self.fault {
+                        Some(_) => VMExit::Faulted,
+                        None => VMExit::Halted,
+                    };
+                }
+                continue;
+            }
+            if let Some(fuel) = self.fuel.as_mut() {
+                *fuel -= 1;
+            }
+            steps += 1;
+            if self.step() {
+                if std::mem::take(&mut self.debugger.paused) {
+                    // breakpoints pause before running the instruction
+                    if self.debugger.resume_at == Some(self.pc) {
+                        if let Some(fuel) = self.fuel.as_mut() {
+                            *fuel += 1;
+                        }
+                    }
+                    break VMExit::Paused;
+                }
+                break match self.fault {
+                    Some(_) => VMExit::Faulted,
+                    None => VMExit::Halted,
+                };
+            }
+        };
+        match exit {
+            VMExit::OutOfFuel => self.event(VMEventType::OutOfFuel),
+            VMExit::TimedOut => self.event(VMEventType::TimedOut),
+            VMExit::Interrupted => self.event(VMEventType::Interrupted),
+            // the breakpoint or watchpoint event says why
+            VMExit::Paused => {}
+            _ => self.event(VMEventType::Stop),
+        }
+        if let Some(tracer) = &self.tracer {
+            let _ = tracer.flush();
+        }
+        exit
+    }
+
+    /// runs the compiled block at pc if there is one and there is fuel for
+    /// all of it, returning how many instructions it ran
+    #[cfg(feature = "jit")]
+    fn run_block(&mut self) -> Option<u64> {
+        // debugging, tracing, profiling and coverage need every step
+        if !self.jit
+            || self.trap_overflow
+            || !self.debugger.is_empty()
+            || self.history.is_some()
+            || self.tracer.is_some()
+            || self.profile.is_some()
+            || self.coverage.is_some()
+        {
+            return None;
+        }
+        let block = self.jit_blocks.block(&self.program, self.pc)?;
+        if self.fuel.is_some_and(|f| f < block.steps) {
+            return None;
+        }
+        self.pc = block.run(
+            &mut self.regs,
+            &mut self.fregs,
+            &mut self.bool_flag,
+            &mut self.flags,
+        );
+        let steps = block.steps;
+        if let Some(fuel) = self.fuel.as_mut() {
+            *fuel -= steps;
+        }
+        Some(steps)
+    }
+
+    /// runs one instruction, true when the VM stopped or paused
+    pub fn step(&mut self) -> bool {
+        if self.pc >= self.program.len() {
+            return true;
+        }
+        let pc = self.pc;
+        if !self.debugger.is_empty() && self.hit_breakpoint(pc) {
+            return true;
+        }
+        let insn = match self.fetch(pc) {
+            Ok(insn) => insn,
+            // only reachable when `program` was set without `load` verifying it
+            Err(DecodeError::BadRegister(r)) => {
+                self.crash(VMFault::BadRegister(pc, r));
+                return true;
+            }
+            Err(DecodeError::Truncated) => {
+                self.crash(VMFault::Truncated(pc));
+                return true;
+            }
+        };
+        let before = self.history.is_some().then(|| self.before_step(pc, insn));
+        let traced = self.tracer.as_ref().is_some_and(|t| t.traces(pc));
+        let regs = traced.then(|| self.before_trace());
+        let started = self.profile.is_some().then(Instant::now);
+        let stop = self.execute(pc, insn);
+        if let (Some(started), Some(profile)) = (started, self.profile.as_mut()) {
+            profile.add(pc, started.elapsed());
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.add(pc, &insn, self.pc);
+        }
+        if let Some(before) = before {
+            self.after_step(before);
+        }
+        if let Some(regs) = regs {
+            self.trace(pc, &insn, regs);
+        }
+        if !stop && !self.debugger.is_empty() {
+            return self.hit_watchpoint(pc);
+        }
+        stop
+    }
+
+    /// `file:line:column` of the instruction at `pc`, from the debug info
+    pub fn location(&self, pc: usize) -> Option<String> {
+        self.debug_info.as_ref()?.location(pc)
+    }
+
+    /// `pc` in hex, followed by its location when there is debug info
+    pub fn describe_pc(&self, pc: usize) -> String {
+        match self.location(pc) {
+            Some(location) => format!("{:#x} ({})", pc, location),
+            None => format!("{:#x}", pc),
+        }
+    }
+
+    /// the instruction at `pc`, see `decode::disassemble`
+    pub fn disassemble(&self, pc: usize) -> String {
+        disassemble(&self.program, pc, self.debug_info.as_ref())
+    }
+
+    pub fn add_breakpoint(&mut self, pc: usize) -> usize {
+        self.debugger.add(Point::Break(pc))
+    }
+
+    /// pauses the VM after an instruction changes `w`
+    pub fn add_watchpoint(&mut self, w: Watch) -> usize {
+        let value = self.watched(w);
+        self.debugger.add(Point::Watch(w, value))
+    }
+
+    pub fn memory(&self, m: Memory) -> &[u8] {
+        match m {
+            Memory::Heap => &self.heap,
+            Memory::Ro => &self.ro_data,
+        }
+    }
+
+    /// writes `bytes` at `addr`, false if they don't fit. Watchpoints take
+    /// the new values as theirs, and a write to a freed block still counts
+    /// as a use after free.
+    pub fn poke(&mut self, m: Memory, addr: usize, bytes: &[u8]) -> bool {
+        let memory = match m {
+            Memory::Heap => &mut self.heap,
+            Memory::Ro => &mut self.ro_data,
+        };
+        match memory.get_mut(addr..addr.saturating_add(bytes.len())) {
+            Some(dest) => dest.copy_from_slice(bytes),
+            None => return false,
+        }
+        self.sync_watchpoints();
+        true
+    }
+
+    /// makes the watchpoints compare with the values now
+    fn sync_watchpoints(&mut self) {
+        for i in 0..self.debugger.points.len() {
+            if let Point::Watch(w, _) = self.debugger.points[i].1 {
+                self.debugger.points[i].1 = Point::Watch(w, self.watched(w));
+            }
+        }
+    }
+
+    fn watched(&self, w: Watch) -> Option<i32> {
+        match w {
+            Watch::Reg(r) => self.regs.get(r).copied(),
+            Watch::Heap(addr) => self.heap.get(addr).map(|&b| b as i32),
+        }
+    }
+
+    fn hit_breakpoint(&mut self, pc: usize) -> bool {
+        // we paused here last time, now go on
+        if self.debugger.resume_at.take() == Some(pc) || !self.debugger.has_breakpoint(pc) {
+            return false;
+        }
+        self.debugger.resume_at = Some(pc);
+        self.debugger.paused = true;
+        self.event(VMEventType::Breakpoint(pc));
+        true
+    }
+
+    /// checks the watchpoints after running the instruction at `pc`
+    fn hit_watchpoint(&mut self, pc: usize) -> bool {
+        let mut hits = vec![];
+        for i in 0..self.debugger.points.len() {
+            if let Point::Watch(w, old) = self.debugger.points[i].1 {
+                let new = self.watched(w);
+                if new != old {
+                    self.debugger.points[i].1 = Point::Watch(w, new);
+                    hits.push(VMEventType::Watchpoint(pc, w, old, new));
+                }
+            }
+        }
+        if hits.is_empty() {
+            return false;
+        }
+        hits.into_iter().for_each(|e| self.event(e));
+        self.debugger.paused = true;
+        true
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    /// replaces the bytecode without checking it, unlike `load`
+    pub fn set_program(&mut self, program: Vec<u8>) {
+        *self.program_mut() = program;
+    }
+
+    /// the bytecode to change in place; what was decoded from it is dropped
+    pub fn program_mut(&mut self) -> &mut Vec<u8> {
+        self.decoded.clear();
+        #[cfg(feature = "jit")]
+        {
+            self.jit_blocks = jit::Jit::default();
+        }
+        &mut self.program
+    }
+
+    /// decodes the whole program, unless that was done since it changed
+    fn predecode(&mut self) {
+        if self.decoded.len() != self.program.len() {
+            let start = PieHeader::parse(&self.program).map_or(0, |h| h.code_offset);
+            self.decoded = predecode(&self.program, start);
+        }
+    }
+
+    /// the decoded instruction at `pc`, from the cache when possible
+    fn fetch(&mut self, pc: usize) -> Result<Insn, DecodeError> {
+        if !self.decode_cache {
+            return decode(&self.program, pc);
+        }
+        self.predecode();
+        match self.decoded[pc] {
+            Some(insn) => Ok(insn),
+            // off the straight line code, e.g. after a jump through a register
+            None => {
+                let insn = decode(&self.program, pc)?;
+                self.decoded[pc] = Some(insn);
+                Ok(insn)
+            }
+        }
+    }
+
+    /// whether nothing needs to see every step, so `run` can go straight
+    /// through the decoded instructions
+    fn unobserved(&self) -> bool {
+        #[cfg(feature = "jit")]
+        if self.jit {
+            return false;
+        }
+        self.decode_cache
+            && self.debugger.is_empty()
+            && self.history.is_none()
+            && self.tracer.is_none()
+            && self.profile.is_none()
+            && self.coverage.is_none()
+    }
+
+    /// runs up to `budget` decoded instructions, returning how many ran and
+    /// whether the VM stopped; anything unusual goes through `step`
+    fn run_decoded(&mut self, budget: u64) -> (u64, bool) {
+        self.predecode();
+        for n in 1..=budget {
+            let pc = self.pc;
+            let stop = match self.decoded.get(pc) {
+                Some(Some(insn)) => self.execute(pc, *insn),
+                _ => self.step(),
+            };
+            if stop {
+                return (n, true);
+            }
+        }
+        (budget, false)
+    }
+
+    fn execute(&mut self, pc: usize, i: Insn) -> bool {
+        // decode checked the registers; the mask only lets the compiler drop
+        // the bounds checks
+        let (a, b, c) = (i.a as usize & 31, i.b as usize & 31, i.c as usize & 31);
+        self.pc = pc + i.len as usize;
+        match i.op {
+            Opcode::NOP => {}
+            Opcode::HLT => return true,
+            Opcode::LOAD => {
+                // format: opcode dst_reg const_num
+                self.regs[a] = i.imm() as i32;
+            }
+            Opcode::MOV => {
+                // format: opcode dst_reg src_reg
+                self.regs[a] = self.regs[b];
+            }
+            Opcode::ADD => {
+                return self.alu_result(pc, c, Flags::add(self.regs[a], self.regs[b]));
+            }
+            Opcode::SUB => {
+                return self.alu_result(pc, c, Flags::sub(self.regs[a], self.regs[b]));
+            }
+            Opcode::MUL => {
+                return self.alu_result(pc, c, Flags::mul(self.regs[a], self.regs[b]));
+            }
+            Opcode::DIV => {
+                let (p, q) = (self.regs[a], self.regs[b]);
+                if q == 0 {
+                    self.crash(VMFault::DivisionByZero(pc));
+                    return true;
+                }
+                let (r, overflow) = p.overflowing_div(q);
+                if self.alu_result(pc, c, (r, Flags::of(r, overflow, overflow))) {
+                    return true;
+                }
+                self.remainder = p.wrapping_rem(q) as u32;
+            }
+            Opcode::NEG => {
+                return self.alu_result(pc, a, Flags::sub(0, self.regs[a]));
+            }
+            Opcode::INC => {
+                return self.alu_result(pc, a, Flags::add(self.regs[a], 1));
+            }
+            Opcode::DEC => {
+                return self.alu_result(pc, a, Flags::sub(self.regs[a], 1));
+            }
+            Opcode::JMP => {
+                self.pc = self.regs[a] as usize;
+            }
+            // relative jumps count from the start of the instruction
+            Opcode::JMPB => {
+                self.pc = pc.wrapping_sub(self.regs[a] as usize);
+            }
+            Opcode::JMPF => {
+                self.pc = pc.wrapping_add(self.regs[a] as usize);
+            }
+            Opcode::BR => {
+                // format: opcode offset16, the offset is signed
+                self.pc = pc.wrapping_add_signed(i.off() as i16 as isize);
+            }
+            Opcode::BEQ => {
+                if self.bool_flag {
+                    self.pc = pc.wrapping_add_signed(i.off() as i16 as isize);
+                }
+            }
+            Opcode::BNE => {
+                if !self.bool_flag {
+                    self.pc = pc.wrapping_add_signed(i.off() as i16 as isize);
+                }
+            }
+            Opcode::EQ => self.bool_flag = self.regs[a] == self.regs[b],
+            Opcode::NEQ => self.bool_flag = self.regs[a] != self.regs[b],
+            Opcode::GT => self.bool_flag = self.regs[a] > self.regs[b],
+            Opcode::GEQ => self.bool_flag = self.regs[a] >= self.regs[b],
+            Opcode::LT => self.bool_flag = self.regs[a] < self.regs[b],
+            Opcode::LEQ => self.bool_flag = self.regs[a] <= self.regs[b],
+            Opcode::OR => {
+                return self.alu_result(pc, c, Flags::logic(self.regs[a] | self.regs[b]));
+            }
+            Opcode::AND => {
+                return self.alu_result(pc, c, Flags::logic(self.regs[a] & self.regs[b]));
+            }
+            Opcode::XOR => {
+                return self.alu_result(pc, c, Flags::logic(self.regs[a] ^ self.regs[b]));
+            }
+            Opcode::NOT => {
+                return self.alu_result(pc, a, Flags::logic(!self.regs[a]));
+            }
+            // shifts and rotates use the low 5 bits of the amount
+            Opcode::SHL => {
+                let r = self.regs[a].wrapping_shl(self.regs[b] as u32);
+                return self.alu_result(pc, c, Flags::logic(r));
+            }
+            Opcode::SHR => {
+                let r = (self.regs[a] as u32).wrapping_shr(self.regs[b] as u32) as i32;
+                return self.alu_result(pc, c, Flags::logic(r));
+            }
+            Opcode::SAR => {
+                let r = self.regs[a].wrapping_shr(self.regs[b] as u32);
+                return self.alu_result(pc, c, Flags::logic(r));
+            }
+            Opcode::ROL => {
+                let r = self.regs[a].rotate_left(self.regs[b] as u32 % 32);
+                return self.alu_result(pc, c, Flags::logic(r));
+            }
+            Opcode::ROR => {
+                let r = self.regs[a].rotate_right(self.regs[b] as u32 % 32);
+                return self.alu_result(pc, c, Flags::logic(r));
+            }
+            Opcode::MOD => {
+                // remainder with the sign of the dividend, like DIV's
+                let (p, q) = (self.regs[a], self.regs[b]);
+                if q == 0 {
+                    self.crash(VMFault::DivisionByZero(pc));
+                    return true;
+                }
+                let (r, overflow) = p.overflowing_rem(q);
+                return self.alu_result(pc, c, (r, Flags::of(r, overflow, overflow)));
+            }
+            Opcode::JEQ => {
+                if self.bool_flag {
+                    self.pc = self.regs[a] as usize;
+                }
+            }
+            Opcode::JNE => {
+                if !self.bool_flag {
+                    self.pc = self.regs[a] as usize;
+                }
+            }
+            Opcode::ALOC => {
+                // format: opcode size_reg dst_reg, dst_reg gets the block address
+                let t = self.regs[a];
+                let size = match usize::try_from(t) {
+                    Ok(size) => size,
+                    Err(_) => {
+                        self.crash(VMFault::BadAllocation(pc, t));
+                        return true;
+                    }
+                };
+                let heap_size = self.heap.len();
+                let res = self
+                    .allocator
+                    .alloc(&mut self.heap, size, self.policy.max_heap);
+                self.heap_growth(pc, heap_size);
+                match res {
+                    Ok(addr) => self.regs[b] = addr as i32,
+                    Err(e) => {
+                        self.alloc_fault(pc, e);
+                        return true;
+                    }
+                }
+            }
+            Opcode::FREE => {
+                let addr = self.regs[a];
+                let res = match usize::try_from(addr) {
+                    Ok(a) => self.allocator.free(&mut self.heap, a),
+                    Err(_) => Err(AllocError::InvalidFree(usize::MAX)),
+                };
+                if let Err(e) = res {
+                    match e {
+                        AllocError::InvalidFree(_) => self.crash(VMFault::InvalidFree(pc, addr)),
+                        e => self.alloc_fault(pc, e),
+                    }
+                    return true;
+                }
+            }
+            Opcode::PRTS => {
+                if !self.policy.allow_output {
+                    self.crash(VMFault::OutputDenied(pc));
+                    return true;
+                }
+                let v: Vec<u8> = self
+                    .ro_data
+                    .get(i.off() as usize..)
+                    .unwrap_or_default()
+                    .iter()
+                    .take_while(|&&b| b != 0)
+                    .cloned()
+                    .collect();
+                match self.output.as_mut() {
+                    Some(out) => {
+                        out.extend_from_slice(&v);
+                        out.push(b'\n');
+                    }
+                    // ro_data is the program's, it needn't be UTF-8
+                    None => println!("{}", String::from_utf8_lossy(&v)),
+                }
+            }
+            Opcode::SYSCALL => {
+                return self.syscall(pc, i.off());
+            }
+            Opcode::LOADF => {
+                // format: opcode dst_freg ro_offset, the f64 is read from ro_data
+                let offs = i.imm() as usize;
+                let f = match self.ro_data.get(offs..offs + 8) {
+                    Some(f) => f.try_into().unwrap(),
+                    None => {
+                        self.crash(VMFault::BadRoOffset(pc, offs));
+                        return true;
+                    }
+                };
+                self.fregs[a] = f64::from_be_bytes(f);
+            }
+            Opcode::ADDF => self.fregs[c] = self.fregs[a] + self.fregs[b],
+            Opcode::SUBF => self.fregs[c] = self.fregs[a] - self.fregs[b],
+            Opcode::MULF => self.fregs[c] = self.fregs[a] * self.fregs[b],
+            Opcode::DIVF => self.fregs[c] = self.fregs[a] / self.fregs[b],
+            Opcode::EQF => self.bool_flag = self.fregs[a] == self.fregs[b],
+            Opcode::NEQF => self.bool_flag = self.fregs[a] != self.fregs[b],
+            Opcode::GTF => self.bool_flag = self.fregs[a] > self.fregs[b],
+            Opcode::GEQF => self.bool_flag = self.fregs[a] >= self.fregs[b],
+            Opcode::LTF => self.bool_flag = self.fregs[a] < self.fregs[b],
+            Opcode::LEQF => self.bool_flag = self.fregs[a] <= self.fregs[b],
+            Opcode::ITOF => {
+                // format: opcode src_reg dst_freg
+                self.fregs[b] = self.regs[a] as f64;
+            }
+            Opcode::FTOI => {
+                // format: opcode src_freg dst_reg, truncates and saturates
+                self.regs[b] = self.fregs[a] as i32;
+            }
+            // format: opcode reg imm16, the immediate is sign extended
+            Opcode::ADDI => {
+                return self.alu_result(pc, a, Flags::add(self.regs[a], i.imm() as i16 as i32));
+            }
+            Opcode::SUBI => {
+                return self.alu_result(pc, a, Flags::sub(self.regs[a], i.imm() as i16 as i32));
+            }
+            Opcode::MULI => {
+                return self.alu_result(pc, a, Flags::mul(self.regs[a], i.imm() as i16 as i32));
+            }
+            Opcode::EQI => self.bool_flag = self.regs[a] == i.imm() as i16 as i32,
+            Opcode::NEQI => self.bool_flag = self.regs[a] != i.imm() as i16 as i32,
+            Opcode::GTI => self.bool_flag = self.regs[a] > i.imm() as i16 as i32,
+            Opcode::LTI => self.bool_flag = self.regs[a] < i.imm() as i16 as i32,
+            Opcode::GEQI => self.bool_flag = self.regs[a] >= i.imm() as i16 as i32,
+            Opcode::LEQI => self.bool_flag = self.regs[a] <= i.imm() as i16 as i32,
+            // format: opcode target_reg, jumps if the flag is (not) set
+            Opcode::JZ | Opcode::JNZ | Opcode::JS | Opcode::JNS => {
+                let flag = match i.op {
+                    Opcode::JZ | Opcode::JNZ => self.flags.zero,
+                    _ => self.flags.negative,
+                };
+                if flag == matches!(i.op, Opcode::JZ | Opcode::JS) {
+                    self.pc = self.regs[a] as usize;
+                }
+            }
+            Opcode::JC | Opcode::JNC | Opcode::JO | Opcode::JNO => {
+                let flag = match i.op {
+                    Opcode::JC | Opcode::JNC => self.flags.carry,
+                    _ => self.flags.overflow,
+                };
+                if flag == matches!(i.op, Opcode::JC | Opcode::JO) {
+                    self.pc = self.regs[a] as usize;
+                }
+            }
+            Opcode::IGL => {
+                self.crash(VMFault::IllegalOpcode(pc, self.program[pc]));
+                return true;
+            }
+        }
+        false
+    }
+
+    fn syscall(&mut self, pc: usize, id: u16) -> bool {
+        if !self.policy.allows_syscall(id) {
+            self.crash(VMFault::SyscallDenied(pc, id));
+            return true;
+        }
+        let f = match self.syscalls.by_id(id) {
+            Some(f) => f.clone(),
+            None => {
+                self.crash(VMFault::UnknownSyscall(pc, id));
+                return true;
+            }
+        };
+        self.event(VMEventType::Syscall(pc, id));
+        let heap_size = self.heap.len();
+        // the allocator's blocks are always inside the heap
+        let allocated = self.allocator.end().unwrap_or(heap_size);
+        let mut ctx = SyscallContext::new(
+            &mut self.regs,
+            &mut self.heap,
+            self.policy.max_heap,
+            allocated,
+        );
+        if self.history.is_some() {
+            ctx.written = Some(vec![]);
+        }
+        let res = f.call(&mut ctx);
+        let refused = ctx.refused;
+        if let (Some(history), Some(written)) = (self.history.as_mut(), ctx.written) {
+            history.written = written;
+        }
+        self.heap_growth(pc, heap_size);
+        match (res, refused) {
+            (_, Some(HeapError::Limit(size))) => {
+                self.crash(VMFault::HeapLimit(pc, size));
+                true
+            }
+            (_, Some(HeapError::Allocated(size))) => {
+                self.crash(VMFault::HeapShrink(pc, size));
+                true
+            }
+            (Ok(v), None) => {
+                self.regs[0] = v;
+                false
+            }
+            (Err(reason), None) => {
+                self.crash(VMFault::Syscall(pc, id, reason));
+                true
+            }
+        }
+    }
+
+    /// sets the flags and writes the result of an ALU op, unless it
+    /// overflowed and the VM traps on overflow: then it faults and returns true
+    fn alu_result(&mut self, pc: usize, dst: usize, (r, flags): (i32, Flags)) -> bool {
+        self.flags = flags;
+        if flags.overflow && self.trap_overflow {
+            self.crash(VMFault::Overflow(pc));
+            return true;
+        }
+        self.regs[dst] = r;
+        false
+    }
+
+    fn alloc_fault(&mut self, pc: usize, e: AllocError) {
+        self.crash(match e {
+            AllocError::HeapLimit(size) => VMFault::HeapLimit(pc, size),
+            AllocError::InvalidFree(addr) => VMFault::InvalidFree(pc, addr as i32),
+            AllocError::DoubleFree(addr) => VMFault::DoubleFree(pc, addr),
+            AllocError::UseAfterFree(addr) => VMFault::UseAfterFree(pc, addr),
+        });
+    }
+
+    fn crash(&mut self, fault: VMFault) {
+        self.event(if fault.is_policy_violation() {
+            VMEventType::PolicyViolation(fault.clone())
+        } else {
+            VMEventType::Fault(fault.clone())
+        });
+        self.fault = Some(fault);
+    }
+
+    fn heap_growth(&mut self, pc: usize, old_size: usize) {
+        if self.heap.len() > old_size {
+            self.event(VMEventType::HeapGrowth(pc, old_size, self.heap.len()));
+        }
+    }
+
+    fn event(&mut self, event: VMEventType) {
+        self.events.push(VMEvent {
+            event,
+            at: Utc::now(),
+            vm_id: self.id,
+        });
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    /// runs `vm`; with the jit feature, copies of it first run with and
+    /// without the jit and must end the same
+    pub fn run(vm: &mut VM) -> VMExit {
+        #[cfg(feature = "jit")]
+        jit::tests::differential(vm);
+        vm.run()
+    }
+    #[test]
+    fn test_create_vm() {
+        let vm = VM::new();
+        for x in vm.regs {
+            assert_eq!(x, 0)
+        }
+    }
+    #[test]
+    fn test_opcode_hlt() {
+        let mut vm = VM::new();
+        let b = vec![Opcode::HLT as u8];
+        vm.set_program(b);
+        run(&mut vm);
+        assert_eq!(vm.pc, 1);
+    }
+    #[test]
+    fn test_opcode_illegal() {
+        let mut vm = VM::new();
+        let b = vec![255, 0, 0];
+        vm.set_program(b);
+        run(&mut vm);
+        assert_eq!(vm.pc, 1);
+    }
+    #[test]
+    fn test_opcode_load() {
+        let mut vm = VM::new();
+        /* 1: load, 0: target register, (1<<8)+244 == 500 */
+        vm.set_program(vec![Opcode::LOAD as u8, 0, 1, 244, Opcode::HLT as u8]);
+        run(&mut vm);
+        assert_eq!(vm.regs[0], 500);
+    }
+    #[test]
+    fn test_opcode_add() {
+        let mut vm = VM::new();
+        vm.set_program(vec![
+            Opcode::LOAD as u8,
+            0,
+            0,
+            1, // regs[0] = 1
+            Opcode::LOAD as u8,
+            1,
+            1,
+            1, // regs[1] = (1<<8)+1 = 257
+            Opcode::ADD as u8,
+            0,
+            1,
+            2, // regs[2] = regs[1]+regs[0]
+            Opcode::HLT as u8,
+        ]); // hlt
+        run(&mut vm);
+        assert_eq!(vm.regs[2], 258);
+    }
+    #[test]
+    fn test_opcode_sub() {
+        let mut vm = VM::new();
+        vm.set_program(vec![
+            Opcode::LOAD as u8,
+            1,
+            0,
+            1, // regs[1] = 1
+            Opcode::LOAD as u8,
+            0,
+            1,
+            1, // regs[0] = (1<<8)+1 = 257
+            Opcode::SUB as u8,
+            0,
+            1,
+            2, // regs[2] = regs[0]-regs[1]
+            Opcode::HLT as u8,
+        ]); // hlt
+        run(&mut vm);
+        assert_eq!(vm.regs[2], 256);
+    }
+    #[test]
+    fn test_opcode_mul() {
+        let mut vm = VM::new();
+        vm.set_program(vec![
+            Opcode::LOAD as u8,
+            0,
+            0,
+            2, // regs[0] = 2
+            Opcode::LOAD as u8,
+            1,
+            1,
+            1, // regs[1] = (1<<8)+1 = 257
+            Opcode::MUL as u8,
+            0,
+            1,
+            2, // regs[2] = regs[1]*regs[0]
+            Opcode::HLT as u8,
+        ]); // hlt
+        run(&mut vm);
+        assert_eq!(vm.regs[2], 257 * 2);
+    }
+    #[test]
+    fn test_opcode_div() {
+        let mut vm = VM::new();
+        vm.set_program(vec![
+            Opcode::LOAD as u8,
+            0,
+            0,
+            2, // regs[0] = 2
+            Opcode::LOAD as u8,
+            1,
+            0,
+            3, // regs[1] = 3
+            Opcode::DIV as u8,
+            1,
+            0,
+            2, // regs[2] = regs[1]/regs[0]
+            Opcode::HLT as u8,
+        ]); // hlt
+        run(&mut vm);
+        assert_eq!(vm.regs[2], 1);
+        assert_eq!(vm.remainder, 1);
+    }
+    #[test]
+    fn test_opcode_jmp() {
+        let mut vm = VM::new();
+        vm.regs[1] = 5;
+        vm.set_program(vec![Opcode::JMP as u8, 1, 255, 255, 255, Opcode::HLT as u8]);
+        vm.step();
+        assert_eq!(vm.pc, 5);
+    }
+    #[test]
+    fn test_opcode_jmpb() {
+        let mut vm = VM::new();
+        vm.regs[1] = 4;
+        vm.pc = 4;
+        vm.set_program(vec![
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+            Opcode::JMPB as u8,
+            1,
+            255,
+            255,
+        ]);
+        run(&mut vm);
+        assert_eq!(vm.pc, 1); // stop after executing hlt
+    }
+    #[test]
+    fn test_opcode_jmpf() {
+        let mut vm = VM::new();
+        vm.regs[1] = 4;
+        vm.set_program(vec![Opcode::JMPF as u8, 1, 255, 255, Opcode::HLT as u8]);
+        vm.step();
+        assert_eq!(vm.pc, 4);
+    }
+    #[test]
+    fn test_opcode_relative_branches() {
+        let mut vm = VM::new();
+        // 0: br +8; 4: hlt; 8: beq -4; 12: bne -12
+        vm.set_program(vec![
+            Opcode::BR as u8,
+            0,
+            8,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+            Opcode::BEQ as u8,
+            0xff,
+            0xfc,
+            0,
+            Opcode::BNE as u8,
+            0xff,
+            0xf4,
+            0,
+        ]);
+        vm.step();
+        assert_eq!(vm.pc, 8);
+        vm.step();
+        assert_eq!(vm.pc, 12); // not taken
+        vm.step();
+        assert_eq!(vm.pc, 0);
+        vm.bool_flag = true;
+        vm.pc = 8;
+        vm.step();
+        assert_eq!(vm.pc, 4);
+    }
+    #[test]
+    fn test_opcode_eq() {
+        let mut vm = VM::new();
+        vm.regs[0] = 1;
+        vm.regs[1] = 1;
+        vm.set_program(vec![Opcode::EQ as u8, 0, 1]);
+        vm.step();
+        assert!(vm.bool_flag);
+        vm.pc = 0;
+        vm.regs[0] = 0;
+        vm.step();
+        assert!(!vm.bool_flag);
+    }
+    #[test]
+    fn test_opcode_neq() {
+        let mut vm = VM::new();
+        vm.regs[0] = 1;
+        vm.regs[1] = 1;
+        vm.set_program(vec![Opcode::NEQ as u8, 0, 1]);
+        vm.step();
+        assert!(!vm.bool_flag);
+        vm.pc = 0;
+        vm.regs[0] = 0;
+        vm.step();
+        assert!(vm.bool_flag);
+    }
+    #[test]
+    fn test_opcode_gt() {
+        let mut vm = VM::new();
+        vm.regs[0] = 1;
+        vm.regs[1] = 1;
+        vm.set_program(vec![Opcode::GT as u8, 0, 1]);
+        vm.step();
+        assert!(!vm.bool_flag);
+        vm.pc = 0;
+        vm.regs[0] = 5;
+        vm.step();
+        assert!(vm.bool_flag);
+    }
+    #[test]
+    fn test_opcode_lt() {
+        let mut vm = VM::new();
+        vm.regs[0] = 1;
+        vm.regs[1] = 1;
+        vm.set_program(vec![Opcode::LT as u8, 0, 1]);
+        vm.step();
+        assert!(!vm.bool_flag);
+        vm.pc = 0;
+        vm.regs[1] = 2;
+        vm.step();
+        assert!(vm.bool_flag);
+    }
+    #[test]
+    fn test_opcode_geq() {
+        let mut vm = VM::new();
+        vm.regs[0] = 1;
+        vm.regs[1] = 1;
+        vm.set_program(vec![Opcode::GEQ as u8, 0, 1]);
+        vm.step();
+        assert!(vm.bool_flag);
+        vm.pc = 0;
+        vm.regs[0] = 0;
+        vm.step();
+        assert!(!vm.bool_flag);
+    }
+    #[test]
+    fn test_opcode_leq() {
+        let mut vm = VM::new();
+        vm.regs[0] = 1;
+        vm.regs[1] = 1;
+        vm.set_program(vec![Opcode::LEQ as u8, 0, 1]);
+        vm.step();
+        assert!(vm.bool_flag);
+        vm.pc = 0;
+        vm.regs[0] = 0;
+        vm.step();
+        assert!(vm.bool_flag);
+    }
+    #[test]
+    fn test_opcode_jeq() {
+        let mut vm = VM::new();
+        vm.bool_flag = true;
+        vm.regs[0] = 5;
+        vm.set_program(vec![Opcode::JEQ as u8, 0, 255, 255, 255, Opcode::HLT as u8]);
+        vm.step();
+        assert_eq!(vm.pc, 5);
+        vm.pc = 0;
+        vm.bool_flag = false;
+        vm.regs[0] = 5;
+        vm.set_program(vec![Opcode::JEQ as u8, 0, 255, 255, 255, Opcode::HLT as u8]);
+        vm.step();
+        assert_eq!(vm.pc, 4);
+    }
+    #[test]
+    fn test_opcode_jne() {
+        let mut vm = VM::new();
+        vm.bool_flag = true;
+        vm.regs[0] = 5;
+        vm.set_program(vec![Opcode::JNE as u8, 0, 255, 255, 255, Opcode::HLT as u8]);
+        vm.step();
+        assert_eq!(vm.pc, 4);
+        vm.pc = 0;
+        vm.bool_flag = false;
+        vm.regs[0] = 5;
+        vm.set_program(vec![Opcode::JNE as u8, 0, 255, 255, 255, Opcode::HLT as u8]);
+        vm.step();
+        assert_eq!(vm.pc, 5);
+    }
+    #[test]
+    fn test_opcode_aloc() {
+        let mut vm = VM::new();
+        vm.regs[0] = 1024;
+        vm.set_program(vec![
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+            Opcode::ALOC as u8,
+            0,
+            2,
+            0,
+        ]);
+        run(&mut vm);
+        assert_eq!(vm.heap.len(), 2048);
+        assert_eq!(vm.regs[1], 0);
+        assert_eq!(vm.regs[2], 1024);
+    }
+    #[test]
+    fn test_opcode_free() {
+        let mut vm = VM::new();
+        vm.set_heap_debug(false);
+        vm.regs[0] = 16;
+        // aloc $0 $1; free $1; aloc $0 $2
+        vm.set_program(vec![
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+            Opcode::FREE as u8,
+            1,
+            0,
+            0,
+            Opcode::ALOC as u8,
+            0,
+            2,
+            0,
+        ]);
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        assert_eq!(vm.regs[2], vm.regs[1]);
+        assert_eq!(vm.heap.len(), 16);
+        let stats = vm.heap_stats();
+        assert_eq!((stats.allocations, stats.frees, stats.in_use), (2, 1, 16));
+    }
+    #[test]
+    fn test_opcode_free_misuse() {
+        let mut vm = VM::new();
+        vm.set_heap_debug(true);
+        vm.regs[0] = 16;
+        vm.regs[3] = 5;
+        // aloc $0 $1; free $1; free $1
+        vm.set_program(vec![
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+            Opcode::FREE as u8,
+            1,
+            0,
+            0,
+            Opcode::FREE as u8,
+            1,
+            0,
+            0,
+        ]);
+        assert_eq!(run(&mut vm), VMExit::Faulted);
+        assert_eq!(vm.fault, Some(VMFault::DoubleFree(8, 0)));
+        vm.set_program(vec![Opcode::FREE as u8, 3, 0, 0]);
+        vm.pc = 0;
+        run(&mut vm);
+        assert_eq!(vm.fault, Some(VMFault::InvalidFree(0, 5)));
+        vm.heap[2] = 1;
+        vm.set_heap_quarantine(0);
+        // aloc $0 $4; free $4: block 0 leaves the quarantine and is checked
+        vm.set_program(vec![
+            Opcode::ALOC as u8,
+            0,
+            4,
+            0,
+            Opcode::FREE as u8,
+            4,
+            0,
+            0,
+        ]);
+        vm.pc = 0;
+        run(&mut vm);
+        assert_eq!(vm.fault, Some(VMFault::UseAfterFree(4, 0)));
+    }
+    #[test]
+    fn test_program_changes() {
+        let mut vm = VM::new();
+        vm.set_program(vec![Opcode::INC as u8, 0, 0, 0]);
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        // the same length, in place: decoded again all the same
+        vm.program_mut()[0] = Opcode::DEC as u8;
+        vm.pc = 0;
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        assert_eq!(vm.regs[0], 0);
+        vm.set_program(vec![Opcode::NEG as u8, 0, 0, 0]);
+        vm.regs[0] = 3;
+        vm.pc = 0;
+        vm.step();
+        assert_eq!(vm.regs[0], -3);
+    }
+    #[test]
+    fn test_unverified_program() {
+        // set directly, so `load` never verified it
+        let mut vm = VM::new();
+        vm.set_program(vec![Opcode::ADD as u8, 1, 40, 0]);
+        assert_eq!(run(&mut vm), VMExit::Faulted);
+        assert_eq!(vm.fault, Some(VMFault::BadRegister(0, 40)));
+        let mut vm = VM::new();
+        vm.set_program(vec![Opcode::INC as u8, 0, 0, 0, Opcode::LOAD as u8, 1]);
+        assert_eq!(run(&mut vm), VMExit::Faulted);
+        assert_eq!(vm.fault, Some(VMFault::Truncated(4)));
+        let mut vm = VM::new();
+        vm.ro_data = vec![0; 8];
+        vm.set_program(vec![Opcode::LOADF as u8, 0, 0, 4]);
+        assert!(vm.step());
+        assert_eq!(vm.fault, Some(VMFault::BadRoOffset(0, 4)));
+    }
+    #[test]
+    fn test_opcode_prts() {
+        let mut vm = VM::new();
+        vm.ro_data = vec![b'h', 0xff, b'i', 0];
+        vm.set_program(vec![
+            Opcode::PRTS as u8,
+            0,
+            0,
+            0,
+            Opcode::PRTS as u8,
+            0,
+            9,
+            0,
+        ]);
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        assert_eq!(vm.fault, None);
+        vm.output = Some(vec![]);
+        vm.pc = 0;
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        assert_eq!(vm.output, Some(b"h\xffi\n\n".to_vec()));
+    }
+    #[test]
+    fn test_opcode_syscall() {
+        let mut vm = VM::new();
+        vm.regs[1] = 20;
+        vm.register_syscall(7, "double", |ctx| Ok(ctx.regs[1] * 2));
+        vm.set_program(vec![Opcode::SYSCALL as u8, 0, 7, 0, Opcode::HLT as u8]);
+        run(&mut vm);
+        assert_eq!(vm.regs[0], 40);
+        assert_eq!(vm.fault, None);
+    }
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let mut vm = VM::new();
+        // 0: inc $1; 4: inc $1; 8: aloc $1 $2; 12: hlt
+        vm.set_program(vec![
+            Opcode::INC as u8,
+            1,
+            0,
+            0,
+            Opcode::INC as u8,
+            1,
+            0,
+            0,
+            Opcode::ALOC as u8,
+            1,
+            2,
+            0,
+            Opcode::HLT as u8,
+        ]);
+        vm.fuel = Some(10);
+        let b = vm.add_breakpoint(4);
+        assert_eq!(run(&mut vm), VMExit::Paused);
+        assert_eq!((vm.pc, vm.regs[1], vm.fuel), (4, 1, Some(9)));
+        assert_eq!(
+            vm.events().last().unwrap().event,
+            VMEventType::Breakpoint(4)
+        );
+        vm.add_watchpoint(Watch::Heap(1));
+        vm.add_watchpoint(Watch::Reg(2));
+        // continuing runs over the breakpoint
+        assert_eq!(run(&mut vm), VMExit::Paused);
+        assert_eq!(vm.pc, 12);
+        // the block's address is 0 again, so only the heap changed
+        assert_eq!(
+            vm.events().last().unwrap().event,
+            VMEventType::Watchpoint(8, Watch::Heap(1), None, Some(0))
+        );
+        assert!(vm.debugger.delete(b));
+        assert_eq!(run(&mut vm), VMExit::Halted);
+    }
+    #[test]
+    fn test_poke() {
+        let mut vm = VM::new();
+        vm.heap = vec![0; 4];
+        vm.ro_data = b"hi\0".to_vec();
+        vm.add_watchpoint(Watch::Heap(1));
+        assert!(vm.poke(Memory::Heap, 1, &[7, 8]));
+        assert_eq!(vm.memory(Memory::Heap), [0, 7, 8, 0]);
+        // the debugger wrote it, not the program
+        assert_eq!(
+            vm.debugger.points[0].1,
+            Point::Watch(Watch::Heap(1), Some(7))
+        );
+        assert!(!vm.poke(Memory::Heap, 3, &[1, 2]));
+        assert!(!vm.poke(Memory::Heap, usize::MAX, &[1]));
+        assert!(vm.poke(Memory::Ro, 0, b"ho"));
+        assert_eq!(vm.memory(Memory::Ro), b"ho\0");
+    }
+    #[test]
+    fn test_events() {
+        let mut vm = VM::new();
+        vm.register_syscall(2, "grow", |ctx| {
+            ctx.resize_heap(8).map_err(|e| format!("{:?}", e))?;
+            Ok(0)
+        });
+        vm.regs[1] = 4;
+        vm.set_program(vec![
+            Opcode::SYSCALL as u8,
+            0,
+            2,
+            0,
+            Opcode::ALOC as u8,
+            1,
+            2,
+            0,
+            Opcode::DIV as u8,
+            1,
+            3,
+            4,
+        ]);
+        run(&mut vm);
+        let events: Vec<VMEventType> = vm.drain_events().map(|e| e.event).collect();
+        assert_eq!(
+            events,
+            vec![
+                VMEventType::Start,
+                VMEventType::Syscall(0, 2),
+                VMEventType::HeapGrowth(0, 0, 8),
+                VMEventType::HeapGrowth(4, 8, 12),
+                VMEventType::Fault(VMFault::DivisionByZero(8)),
+                VMEventType::Stop,
+            ]
+        );
+        assert!(vm.events().is_empty());
+        vm.pc = 8;
+        run(&mut vm);
+        let mut out = vec![];
+        vm.write_events(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 3);
+        assert!(out.contains("\"event\":\"fault\",\"pc\":8,\"reason\":\"division by zero\"}"));
+    }
+    #[test]
+    fn test_opcode_syscall_fault() {
+        let mut vm = VM::new();
+        vm.register_syscall(1, "fail", |_| Err("no such file".to_string()));
+        vm.set_program(vec![
+            Opcode::SYSCALL as u8,
+            0,
+            1,
+            0,
+            Opcode::SYSCALL as u8,
+            0,
+            2,
+            0,
+        ]);
+        run(&mut vm);
+        assert_eq!(
+            vm.fault,
+            Some(VMFault::Syscall(0, 1, "no such file".to_string()))
+        );
+        vm.pc = 4;
+        run(&mut vm);
+        assert_eq!(vm.fault, Some(VMFault::UnknownSyscall(4, 2)));
+    }
+    #[test]
+    fn test_load_checks_imports() {
+        let mut asm = crate::asm::Assembler::new();
+        let pie = asm
+            .assemble(
+                ".data
+                now: .host #3
+                .code
+                syscall @now",
+            )
+            .unwrap();
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.load(pie.clone(), vec![]),
+            Err(LoadError::MissingHostFunction(3, "now".to_string()))
+        );
+        vm.register_syscall(3, "now", |_| Ok(1234));
+        assert_eq!(vm.load(pie, vec![]), Ok(()));
+        run(&mut vm);
+        assert_eq!(vm.regs[0], 1234);
+    }
+    #[test]
+    fn test_load_verifies_code() {
+        let mut asm = crate::asm::Assembler::new();
+        let mut pie = asm.assemble(".code\nprts #0\nhlt").unwrap();
+        let mut vm = VM::new();
+        let code = pie.len() - 8;
+        assert_eq!(
+            vm.load(pie.clone(), vec![]),
+            Err(LoadError::Invalid(vec![Diagnostic::BadRoOffset(code, 0)]))
+        );
+        assert_eq!(vm.load(pie.clone(), b"hi\0".to_vec()), Ok(()));
+        assert_eq!(vm.ro_data, b"hi\0");
+        pie[code] = Opcode::IGL as u8;
+        assert_eq!(
+            vm.load(pie, vec![]),
+            Err(LoadError::Invalid(vec![Diagnostic::UnknownOpcode(
+                code,
+                Opcode::IGL as u8
+            )]))
+        );
+    }
+    #[test]
+    fn test_run_out_of_fuel() {
+        let mut vm = VM::new();
+        // loop: inc $1; jmp $0
+        vm.set_program(vec![Opcode::INC as u8, 1, 0, 0, Opcode::JMP as u8, 0, 0, 0]);
+        vm.fuel = Some(10);
+        assert_eq!(run(&mut vm), VMExit::OutOfFuel);
+        assert_eq!(vm.regs[1], 5);
+        assert_eq!(vm.fuel, Some(0));
+        vm.fuel = Some(3);
+        assert_eq!(run(&mut vm), VMExit::OutOfFuel);
+        assert_eq!(vm.regs[1], 7);
+        assert!(matches!(
+            vm.events.last().unwrap().event,
+            VMEventType::OutOfFuel
+        ));
+        vm.set_program(vec![Opcode::HLT as u8]);
+        vm.pc = 0;
+        vm.fuel = Some(1);
+        assert_eq!(run(&mut vm), VMExit::Halted);
+    }
+    #[test]
+    fn test_run_timed_out() {
+        let mut vm = VM::new();
+        vm.set_program(vec![Opcode::JMP as u8, 0, 0, 0]);
+        vm.deadline = Some(Utc::now() + chrono::Duration::milliseconds(20));
+        assert_eq!(run(&mut vm), VMExit::TimedOut);
+        assert_eq!(vm.pc, 0);
+        assert!(matches!(
+            vm.events.last().unwrap().event,
+            VMEventType::TimedOut
+        ));
+    }
+    #[test]
+    fn test_run_interrupted() {
+        let mut vm = VM::new();
+        vm.set_program(vec![Opcode::JMP as u8, 0, 0, 0]);
+        let interrupt = vm.interrupt.clone();
+        // it loops until interrupted, so it can't be compared with the jit
+        let handle = std::thread::spawn(move || {
+            let exit = vm.run();
+            (vm, exit)
+        });
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        interrupt.store(true, Ordering::Relaxed);
+        let (vm, exit) = handle.join().unwrap();
+        assert_eq!(exit, VMExit::Interrupted);
+        assert!(!vm.interrupt.load(Ordering::Relaxed));
+        assert!(matches!(
+            vm.events.last().unwrap().event,
+            VMEventType::Interrupted
+        ));
+    }
+    #[test]
+    fn test_run_faulted() {
+        let mut vm = VM::new();
+        vm.set_program(vec![Opcode::IGL as u8]);
+        assert_eq!(run(&mut vm), VMExit::Faulted);
+    }
+    #[test]
+    fn test_opcode_aloc_negative() {
+        let mut vm = VM::new();
+        vm.regs[0] = -1;
+        vm.set_program(vec![Opcode::ALOC as u8, 0, 0, 0]);
+        assert_eq!(run(&mut vm), VMExit::Faulted);
+        assert_eq!(vm.fault, Some(VMFault::BadAllocation(0, -1)));
+        assert!(vm.heap.is_empty());
+    }
+    #[test]
+    fn test_policy_heap_limit() {
+        let mut vm = VM::new();
+        vm.policy.max_heap = Some(1024);
+        vm.regs[0] = 1000;
+        vm.set_program(vec![
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+        ]);
+        assert_eq!(run(&mut vm), VMExit::Faulted);
+        assert_eq!(vm.fault, Some(VMFault::HeapLimit(4, 2000)));
+        assert_eq!(vm.heap.len(), 1000);
+        assert_eq!(
+            vm.events()[vm.events.len() - 2].event,
+            VMEventType::PolicyViolation(VMFault::HeapLimit(4, 2000))
+        );
+    }
+    #[test]
+    fn test_policy_sandbox() {
+        let mut vm = VM::new();
+        vm.policy = Policy::sandbox(0);
+        vm.register_syscall(1, "grow", |ctx| {
+            // ignoring the refusal doesn't help
+            let _ = ctx.resize_heap(10);
+            Ok(0)
+        });
+        vm.ro_data = vec![b'h', b'i', 0];
+        vm.set_program(vec![Opcode::PRTS as u8, 0, 0, 0]);
+        run(&mut vm);
+        assert_eq!(vm.fault, Some(VMFault::OutputDenied(0)));
+        vm.set_program(vec![Opcode::SYSCALL as u8, 0, 1, 0]);
+        vm.pc = 0;
+        run(&mut vm);
+        assert_eq!(vm.fault, Some(VMFault::SyscallDenied(0, 1)));
+        vm.policy.allowed_syscalls = Some(vec![1]);
+        vm.pc = 0;
+        run(&mut vm);
+        assert_eq!(vm.fault, Some(VMFault::HeapLimit(0, 10)));
+        assert!(vm.heap.is_empty());
+    }
+    #[test]
+    fn test_syscall_heap_shrink() {
+        let mut vm = VM::new();
+        vm.register_syscall(1, "shrink", |ctx| match ctx.resize_heap(2) {
+            Ok(()) => Ok(0),
+            Err(e) => Err(format!("{:?}", e)),
+        });
+        vm.regs[0] = 4;
+        vm.set_program(vec![
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+            Opcode::SYSCALL as u8,
+            0,
+            1,
+            0,
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+        ]);
+        assert_eq!(run(&mut vm), VMExit::Faulted);
+        assert_eq!(vm.fault, Some(VMFault::HeapShrink(4, 2)));
+        assert_eq!(vm.heap.len(), 4);
+    }
+    #[test]
+    fn test_opcode_float_arith() {
+        let mut vm = VM::new();
+        vm.ro_data = [1.5f64.to_be_bytes(), 4.0f64.to_be_bytes()].concat();
+        vm.set_program(vec![
+            Opcode::LOADF as u8,
+            0,
+            0,
+            0, // f0 = 1.5
+            Opcode::LOADF as u8,
+            1,
+            0,
+            8, // f1 = 4.0
+            Opcode::ADDF as u8,
+            0,
+            1,
+            2, // f2 = 5.5
+            Opcode::SUBF as u8,
+            0,
+            1,
+            3, // f3 = -2.5
+            Opcode::MULF as u8,
+            0,
+            1,
+            4, // f4 = 6.0
+            Opcode::DIVF as u8,
+            0,
+            1,
+            5, // f5 = 0.375
+            Opcode::GTF as u8,
+            1,
+            0,
+            0,
+        ]);
+        run(&mut vm);
+        assert_eq!(vm.fregs[..6], [1.5, 4.0, 5.5, -2.5, 6.0, 0.375]);
+        assert!(vm.bool_flag);
+    }
+    #[test]
+    fn test_opcode_float_compare() {
+        let mut vm = VM::new();
+        vm.fregs[0] = 1.0;
+        vm.fregs[1] = f64::NAN;
+        for (op, expected) in [
+            (Opcode::EQF, false),
+            (Opcode::NEQF, true),
+            (Opcode::LTF, false),
+            (Opcode::LEQF, false),
+            (Opcode::GEQF, false),
+        ] {
+            vm.set_program(vec![op as u8, 0, 1, 0]);
+            vm.pc = 0;
+            vm.step();
+            assert_eq!(vm.bool_flag, expected, "{:?}", op);
+        }
+    }
+    #[test]
+    fn test_opcode_float_conversions() {
+        let mut vm = VM::new();
+        vm.regs[0] = -7;
+        vm.fregs[1] = 2.9;
+        vm.fregs[2] = 1e20;
+        vm.set_program(vec![
+            Opcode::ITOF as u8,
+            0,
+            3,
+            0,
+            Opcode::FTOI as u8,
+            1,
+            4,
+            0,
+            Opcode::FTOI as u8,
+            2,
+            5,
+            0,
+        ]);
+        run(&mut vm);
+        assert_eq!(vm.fregs[3], -7.0);
+        assert_eq!(vm.regs[4], 2);
+        assert_eq!(vm.regs[5], i32::MAX);
+    }
+    fn alu(op: Opcode, p: i32, q: i32) -> VM {
+        let mut vm = VM::new();
+        vm.regs[0] = p;
+        vm.regs[1] = q;
+        vm.set_program(vec![op as u8, 0, 1, 2]);
+        run(&mut vm);
+        vm
+    }
+    #[test]
+    fn test_opcode_not() {
+        let mut vm = VM::new();
+        vm.regs[3] = 0x0f0f;
+        vm.set_program(vec![Opcode::NOT as u8, 3, 0, 0]);
+        run(&mut vm);
+        assert_eq!(vm.regs[3], !0x0f0f);
+    }
+    #[test]
+    fn test_opcode_xor() {
+        assert_eq!(alu(Opcode::XOR, 0b1100, 0b1010).regs[2], 0b0110);
+        assert_eq!(alu(Opcode::XOR, -1, 5).regs[2], !5);
+    }
+    #[test]
+    fn test_opcode_shifts() {
+        assert_eq!(alu(Opcode::SHL, 1, 4).regs[2], 16);
+        assert_eq!(alu(Opcode::SHL, 1, 31).regs[2], i32::MIN);
+        assert_eq!(alu(Opcode::SHL, 1, 33).regs[2], 2); // amount wraps at 32
+        assert_eq!(alu(Opcode::SHR, -16, 2).regs[2], 0x3ffffffc);
+        assert_eq!(alu(Opcode::SAR, -16, 2).regs[2], -4);
+        assert_eq!(alu(Opcode::SAR, i32::MIN, 31).regs[2], -1);
+        assert_eq!(alu(Opcode::SHR, i32::MIN, 31).regs[2], 1);
+    }
+    #[test]
+    fn test_opcode_rotates() {
+        assert_eq!(alu(Opcode::ROL, i32::MIN | 1, 1).regs[2], 3);
+        assert_eq!(alu(Opcode::ROR, 3, 1).regs[2], i32::MIN | 1);
+        assert_eq!(alu(Opcode::ROL, 0x1234, 32).regs[2], 0x1234);
+        assert_eq!(alu(Opcode::ROR, 0x1234, -4).regs[2], 0x12340);
+    }
+    #[test]
+    fn test_opcode_mod() {
+        assert_eq!(alu(Opcode::MOD, 7, 3).regs[2], 1);
+        assert_eq!(alu(Opcode::MOD, -7, 3).regs[2], -1);
+        assert_eq!(alu(Opcode::MOD, i32::MIN, -1).regs[2], 0);
+        assert_eq!(
+            alu(Opcode::MOD, 7, 0).fault,
+            Some(VMFault::DivisionByZero(0))
+        );
+        let vm = alu(Opcode::DIV, i32::MIN, -1);
+        assert_eq!((vm.regs[2], vm.remainder), (i32::MIN, 0));
+        assert_eq!(
+            alu(Opcode::DIV, 7, 0).fault,
+            Some(VMFault::DivisionByZero(0))
+        );
+    }
+    #[test]
+    fn test_opcode_immediates() {
+        let mut vm = VM::new();
+        vm.regs[1] = 10;
+        vm.set_program(vec![
+            Opcode::ADDI as u8,
+            1,
+            0,
+            5, // $1 = 15
+            Opcode::SUBI as u8,
+            1,
+            0xff,
+            0xff, // $1 = 16
+            Opcode::MULI as u8,
+            1,
+            0,
+            3, // $1 = 48
+            Opcode::GTI as u8,
+            1,
+            0,
+            47,
+        ]);
+        run(&mut vm);
+        assert_eq!(vm.regs[1], 48);
+        assert!(vm.bool_flag);
+        for (op, imm, expected) in [
+            (Opcode::EQI, 48, true),
+            (Opcode::NEQI, 48, false),
+            (Opcode::LTI, 49, true),
+            (Opcode::LEQI, 47, false),
+            (Opcode::GEQI, 48, true),
+        ] {
+            vm.set_program(vec![op as u8, 1, 0, imm]);
+            vm.pc = 0;
+            vm.step();
+            assert_eq!(vm.bool_flag, expected, "{:?}", op);
+        }
+        vm.regs[2] = i32::MAX;
+        vm.set_program(vec![Opcode::ADDI as u8, 2, 0, 1]);
+        vm.pc = 0;
+        vm.step();
+        assert_eq!(vm.regs[2], i32::MIN);
+    }
+    #[test]
+    fn test_alu_flags() {
+        let vm = alu(Opcode::ADD, i32::MAX, 1);
+        assert_eq!(vm.regs[2], i32::MIN);
+        assert_eq!(vm.flags, Flags::of(i32::MIN, false, true));
+        let vm = alu(Opcode::SUB, 3, 3);
+        assert!(vm.flags.zero && !vm.flags.carry);
+        let vm = alu(Opcode::SUB, 2, 3);
+        assert!(vm.flags.negative && vm.flags.carry);
+        let vm = alu(Opcode::MUL, 1 << 20, 1 << 20);
+        assert!(vm.flags.overflow);
+        let vm = alu(Opcode::XOR, 5, 5);
+        assert_eq!(vm.flags, Flags::of(0, false, false));
+        let vm = alu(Opcode::DIV, i32::MIN, -1);
+        assert!(vm.flags.overflow);
+        let mut vm = VM::new();
+        vm.regs[0] = i32::MIN;
+        vm.set_program(vec![Opcode::NEG as u8, 0, 0, 0, Opcode::INC as u8, 1, 0, 0]);
+        vm.step();
+        assert!(vm.flags.overflow);
+        vm.step();
+        assert_eq!(vm.flags, Flags::default());
+    }
+    #[test]
+    fn test_trap_overflow() {
+        let mut vm = VM::new();
+        vm.trap_overflow = true;
+        vm.regs[0] = i32::MAX;
+        vm.regs[1] = 1;
+        vm.regs[2] = 7;
+        vm.set_program(vec![Opcode::ADD as u8, 0, 1, 2, Opcode::ADD as u8, 1, 1, 2]);
+        assert_eq!(run(&mut vm), VMExit::Faulted);
+        assert_eq!(vm.fault, Some(VMFault::Overflow(0)));
+        assert_eq!(vm.regs[2], 7); // the result isn't written
+        vm.trap_overflow = false;
+        vm.pc = 0;
+        assert_eq!(run(&mut vm), VMExit::Halted);
+        assert_eq!(vm.regs[2], 2);
+    }
+    #[test]
+    fn test_opcode_flag_jumps() {
+        let mut vm = VM::new();
+        vm.regs[0] = 12;
+        for (op, flags, taken) in [
+            (Opcode::JZ, Flags::of(0, false, false), true),
+            (Opcode::JNZ, Flags::of(0, false, false), false),
+            (Opcode::JS, Flags::of(-1, false, false), true),
+            (Opcode::JNS, Flags::of(-1, false, false), false),
+            (Opcode::JC, Flags::of(1, true, false), true),
+            (Opcode::JNC, Flags::of(1, true, false), false),
+            (Opcode::JO, Flags::of(1, false, true), true),
+            (Opcode::JNO, Flags::of(1, false, false), true),
+        ] {
+            vm.flags = flags;
+            vm.set_program(vec![op as u8, 0, 0, 0]);
+            vm.pc = 0;
+            vm.step();
+            assert_eq!(vm.pc, if taken { 12 } else { 4 }, "{:?}", op);
+        }
+    }
+}
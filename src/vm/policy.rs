@@ -0,0 +1,64 @@
+/// What a program running in a VM may do. `step` checks it and faults on
+/// violations, so untrusted programs can't take the host down with them.
+///
+/// There is no stack limit: the VM has no stack or call instructions, so the
+/// heap is the only memory a program can grow.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    pub max_heap: Option<usize>,            // bytes, None for no limit
+    pub allowed_syscalls: Option<Vec<u16>>, // host function ids, None allows all
+    pub allow_output: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Policy {
+    /// a policy allowing everything
+    pub fn new() -> Policy {
+        Policy {
+            max_heap: None,
+            allowed_syscalls: None,
+            allow_output: true,
+        }
+    }
+
+    /// a policy allowing no syscalls, no output and at most `max_heap` bytes of heap
+    pub fn sandbox(max_heap: usize) -> Policy {
+        Policy {
+            max_heap: Some(max_heap),
+            allowed_syscalls: Some(vec![]),
+            allow_output: false,
+        }
+    }
+
+    pub fn allows_heap(&self, size: usize) -> bool {
+        self.max_heap.is_none_or(|max| size <= max)
+    }
+
+    pub fn allows_syscall(&self, id: u16) -> bool {
+        self.allowed_syscalls
+            .as_ref()
+            .is_none_or(|ids| ids.contains(&id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_policy() {
+        let p = Policy::new();
+        assert!(p.allows_heap(usize::MAX));
+        assert!(p.allows_syscall(3));
+        let mut p = Policy::sandbox(16);
+        assert!(p.allows_heap(16));
+        assert!(!p.allows_heap(17));
+        assert!(!p.allows_syscall(3));
+        p.allowed_syscalls = Some(vec![3]);
+        assert!(p.allows_syscall(3));
+    }
+}
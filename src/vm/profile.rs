@@ -0,0 +1,216 @@
+use std::io;
+use std::time::Duration;
+
+use super::decode::disassemble;
+use crate::asm::debug::DebugInfo;
+use crate::asm::SymbolTable;
+use crate::instruction::Opcode;
+
+/// Executions and time per pc, counted by `VM::step` while the VM has one
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub counts: Vec<u64>, // by pc
+    pub nanos: Vec<u64>,  // by pc
+}
+
+impl Profile {
+    pub(super) fn add(&mut self, pc: usize, elapsed: Duration) {
+        if pc >= self.counts.len() {
+            self.counts.resize(pc + 1, 0);
+            self.nanos.resize(pc + 1, 0);
+        }
+        self.counts[pc] += 1;
+        self.nanos[pc] += elapsed.as_nanos() as u64;
+    }
+
+    /// instructions run and nanoseconds spent in them
+    pub fn total(&self) -> (u64, u64) {
+        (self.counts.iter().sum(), self.nanos.iter().sum())
+    }
+
+    /// pc, count and nanoseconds of the instructions that ran, most run first
+    pub fn hot(&self) -> Vec<(usize, u64, u64)> {
+        let mut hot: Vec<(usize, u64, u64)> = (0..self.counts.len())
+            .filter(|&pc| self.counts[pc] > 0)
+            .map(|pc| (pc, self.counts[pc], self.nanos[pc]))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot
+    }
+
+    /// the same by opcode, most run first
+    pub fn by_opcode(&self, program: &[u8]) -> Vec<(Opcode, u64, u64)> {
+        let mut ops: Vec<(Opcode, u64, u64)> = vec![];
+        for (pc, count, nanos) in self.hot() {
+            let op = program.get(pc).map_or(Opcode::IGL, |&b| Opcode::from(b));
+            match ops.iter_mut().find(|e| e.0 == op) {
+                Some(e) => {
+                    e.1 += count;
+                    e.2 += nanos;
+                }
+                None => ops.push((op, count, nanos)),
+            }
+        }
+        ops.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.to_string().cmp(&b.0.to_string())));
+        ops
+    }
+
+    /// the `top` hottest addresses, with their source lines when there is
+    /// debug info, labels and instructions, and the opcodes
+    pub fn report(
+        &self,
+        program: &[u8],
+        symbols: &SymbolTable,
+        debug: Option<&DebugInfo>,
+        top: usize,
+    ) -> String {
+        let (count, nanos) = self.total();
+        let percent = |c: u64| 100.0 * c as f64 / count.max(1) as f64;
+        let mut out = format!(
+            "{} instructions in {:?}\n\nHot addresses:\n{:>12} {:>7} {:>12}  {:<8} {:<16} {:<16} instruction\n",
+            count,
+            Duration::from_nanos(nanos),
+            "count",
+            "%",
+            "time",
+            "address",
+            "source",
+            "label"
+        );
+        for (pc, c, n) in self.hot().into_iter().take(top) {
+            let label = match symbols.code_label_at(pc) {
+                Some((label, 0)) => label.to_string(),
+                Some((label, off)) => format!("{}+{}", label, off),
+                None => String::new(),
+            };
+            out.push_str(&format!(
+                "{:>12} {:>6.2}% {:>12}  {:<8} {:<16} {:<16} {}\n",
+                c,
+                percent(c),
+                format!("{:?}", Duration::from_nanos(n)),
+                format!("{:#06x}", pc),
+                debug.and_then(|d| d.source_line(pc)).unwrap_or_default(),
+                label,
+                disassemble(program, pc, None)
+            ));
+        }
+        out.push_str(&format!(
+            "\nOpcodes:\n{:>12} {:>7} {:>12}  opcode\n",
+            "count", "%", "time"
+        ));
+        for (op, c, n) in self.by_opcode(program) {
+            out.push_str(&format!(
+                "{:>12} {:>6.2}% {:>12}  {}\n",
+                c,
+                percent(c),
+                format!("{:?}", Duration::from_nanos(n)),
+                op.to_string().to_lowercase()
+            ));
+        }
+        out
+    }
+
+    /// folded stacks for flamegraph tools, weighted by executions, with the
+    /// label code is under as the caller of its source lines when there is
+    /// debug info, or of its instructions when there isn't
+    ///
+    /// there are no calls in the VM, so the labels are the only frames
+    pub fn write_folded<W: io::Write>(
+        &self,
+        w: &mut W,
+        program: &[u8],
+        symbols: &SymbolTable,
+        debug: Option<&DebugInfo>,
+    ) -> io::Result<()> {
+        let mut hot = self.hot();
+        hot.sort_by_key(|&(pc, _, _)| pc);
+        let mut stacks: Vec<(String, u64)> = vec![];
+        for (pc, count, _) in hot {
+            let label = symbols.code_label_at(pc).map_or("[unlabeled]", |(l, _)| l);
+            let frame = match debug.and_then(|d| d.source_line(pc)) {
+                Some(line) => line,
+                None => format!("{:#06x} {}", pc, disassemble(program, pc, None)),
+            };
+            let stack = format!("{};{}", label, frame);
+            match stacks.iter_mut().find(|(s, _)| *s == stack) {
+                Some((_, c)) => *c += count,
+                None => stacks.push((stack, count)),
+            }
+        }
+        for (stack, count) in stacks {
+            writeln!(w, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::vm::tests::run;
+    use crate::vm::VM;
+    #[test]
+    fn test_profile() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(
+                ".data
+            .code
+            load $0 #3
+            loop: dec $0
+            neq $0 $1
+            beq @loop
+            hlt",
+            )
+            .unwrap();
+        let mut vm = VM::new();
+        vm.load(program, vec![]).unwrap();
+        vm.profile = Some(Profile::default());
+        run(&mut vm);
+        let profile = vm.profile.as_ref().unwrap();
+        let dec = asm.symbols.symbol_value("loop").unwrap() as usize;
+        assert_eq!(profile.total().0, 11);
+        assert_eq!(profile.hot()[0].0, dec);
+        assert_eq!(profile.hot()[0].1, 3);
+        let ops = profile.by_opcode(&vm.program);
+        assert_eq!((ops[0].0, ops[0].1), (Opcode::BEQ, 3));
+        assert_eq!((ops[4].0, ops[4].1), (Opcode::LOAD, 1));
+
+        let report = profile.report(&vm.program, &asm.symbols, None, 2);
+        assert!(report.starts_with("11 instructions in "));
+        assert!(report.contains(&format!("{:<16} loop             dec $0\n", "")));
+        assert!(report.contains("loop+4           neq $0 $1\n"));
+        assert!(!report.contains("hlt\n\n"));
+
+        let mut folded = vec![];
+        profile
+            .write_folded(&mut folded, &vm.program, &asm.symbols, None)
+            .unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert_eq!(
+            folded.lines().collect::<Vec<_>>(),
+            vec![
+                format!("[unlabeled];{:#06x} load $0 #3 1", dec - 4),
+                format!("loop;{:#06x} dec $0 3", dec),
+                format!("loop;{:#06x} neq $0 $1 3", dec + 4),
+                format!("loop;{:#06x} beq -8 3", dec + 8),
+                format!("loop;{:#06x} hlt 1", dec + 12),
+            ]
+        );
+
+        // with debug info, source lines
+        asm.file = "p.asm".to_string();
+        let debug = asm.debug_info();
+        let report = profile.report(&vm.program, &asm.symbols, Some(&debug), 2);
+        assert!(report.contains("p.asm:4          loop             dec $0\n"));
+        let mut folded = vec![];
+        profile
+            .write_folded(&mut folded, &vm.program, &asm.symbols, Some(&debug))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "[unlabeled];p.asm:3 1\nloop;p.asm:4 3\nloop;p.asm:5 3\nloop;p.asm:6 3\nloop;p.asm:7 1\n"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use chrono::{DateTime, Utc};
+
+use super::alloc::Allocator;
+use super::debug::Watch;
+use super::decode::predecode;
+use super::flags::Flags;
+use super::{LoadError, VMEvent, VMEventType, VMFault, VM};
+use crate::asm::debug::DebugInfo;
+
+pub const SNAPSHOT_PREFIX: [u8; 4] = [0x7e, b'R', b'V', b'S'];
+/// bumped whenever the layout below changes; older snapshots are rejected
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotError {
+    BadPrefix,
+    UnsupportedVersion(u16), // version of the snapshot
+    Truncated,
+    Invalid(&'static str), // what
+    Program(LoadError),    // the program fails the checks `load` makes
+}
+
+/// Big endian encoder for snapshots
+#[derive(Default)]
+pub struct Writer {
+    pub bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    /// length prefixed
+    pub fn slice(&mut self, v: &[u8]) {
+        self.u64(v.len() as u64);
+        self.bytes.extend_from_slice(v);
+    }
+}
+
+/// Decoder for what `Writer` wrote
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if n > self.bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+        let (v, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(v)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut a = [0; N];
+        a.copy_from_slice(self.take(N)?);
+        Ok(a)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(self.u64()?).map_err(|_| SnapshotError::Invalid("size"))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn vec(&mut self) -> Result<Vec<u8>, SnapshotError> {
+        let len = self.usize()?;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+fn write_fault(w: &mut Writer, fault: &VMFault) {
+    // tag, pc, the other number if any, the host's reason if any
+    let (tag, n, reason) = match fault {
+        VMFault::IllegalOpcode(_, op) => (0, *op as u64, ""),
+        VMFault::DivisionByZero(_) => (1, 0, ""),
+        VMFault::Overflow(_) => (2, 0, ""),
+        VMFault::UnknownSyscall(_, id) => (3, *id as u64, ""),
+        VMFault::Syscall(_, id, reason) => (4, *id as u64, reason.as_str()),
+        VMFault::BadAllocation(_, size) => (5, *size as u32 as u64, ""),
+        VMFault::InvalidFree(_, addr) => (6, *addr as u32 as u64, ""),
+        VMFault::DoubleFree(_, addr) => (7, *addr as u64, ""),
+        VMFault::UseAfterFree(_, addr) => (8, *addr as u64, ""),
+        VMFault::HeapLimit(_, size) => (9, *size as u64, ""),
+        VMFault::SyscallDenied(_, id) => (10, *id as u64, ""),
+        VMFault::OutputDenied(_) => (11, 0, ""),
+        VMFault::HeapShrink(_, size) => (12, *size as u64, ""),
+        VMFault::BadRegister(_, r) => (13, *r as u64, ""),
+        VMFault::Truncated(_) => (14, 0, ""),
+        VMFault::BadRoOffset(_, off) => (15, *off as u64, ""),
+    };
+    w.u8(tag);
+    w.u64(fault.pc() as u64);
+    w.u64(n);
+    w.slice(reason.as_bytes());
+}
+
+fn read_fault(r: &mut Reader) -> Result<VMFault, SnapshotError> {
+    let (tag, pc, n) = (r.u8()?, r.usize()?, r.u64()?);
+    let reason = String::from_utf8(r.vec()?).map_err(|_| SnapshotError::Invalid("fault"))?;
+    Ok(match tag {
+        0 => VMFault::IllegalOpcode(pc, n as u8),
+        1 => VMFault::DivisionByZero(pc),
+        2 => VMFault::Overflow(pc),
+        3 => VMFault::UnknownSyscall(pc, n as u16),
+        4 => VMFault::Syscall(pc, n as u16, reason),
+        5 => VMFault::BadAllocation(pc, n as u32 as i32),
+        6 => VMFault::InvalidFree(pc, n as u32 as i32),
+        7 => VMFault::DoubleFree(pc, n as usize),
+        8 => VMFault::UseAfterFree(pc, n as usize),
+        9 => VMFault::HeapLimit(pc, n as usize),
+        10 => VMFault::SyscallDenied(pc, n as u16),
+        11 => VMFault::OutputDenied(pc),
+        12 => VMFault::HeapShrink(pc, n as usize),
+        13 => VMFault::BadRegister(pc, n as u8),
+        14 => VMFault::Truncated(pc),
+        15 => VMFault::BadRoOffset(pc, n as usize),
+        _ => return Err(SnapshotError::Invalid("fault")),
+    })
+}
+
+fn write_event(w: &mut Writer, event: &VMEventType) {
+    // tag, then up to three numbers or a fault
+    let (tag, nums) = match event {
+        VMEventType::Start => (0, vec![]),
+        VMEventType::Stop => (1, vec![]),
+        VMEventType::Fault(_) => (2, vec![]),
+        VMEventType::PolicyViolation(_) => (3, vec![]),
+        VMEventType::OutOfFuel => (4, vec![]),
+        VMEventType::TimedOut => (5, vec![]),
+        VMEventType::Syscall(pc, id) => (6, vec![*pc as u64, *id as u64]),
+        VMEventType::HeapGrowth(pc, from, to) => (7, vec![*pc as u64, *from as u64, *to as u64]),
+        VMEventType::Breakpoint(pc) => (8, vec![*pc as u64]),
+        VMEventType::Watchpoint(pc, w, old, new) => {
+            let (kind, at) = match w {
+                Watch::Reg(r) => (0, *r),
+                Watch::Heap(addr) => (1, *addr),
+            };
+            // values as present flag and value
+            let value = |v: &Option<i32>| [v.is_some() as u64, v.unwrap_or(0) as u32 as u64];
+            let mut nums = vec![*pc as u64, kind, at as u64];
+            nums.extend(value(old));
+            nums.extend(value(new));
+            (9, nums)
+        }
+        VMEventType::Interrupted => (10, vec![]),
+    };
+    w.u8(tag);
+    match event {
+        VMEventType::Fault(f) | VMEventType::PolicyViolation(f) => write_fault(w, f),
+        _ => nums.into_iter().for_each(|n| w.u64(n)),
+    }
+}
+
+fn read_event(r: &mut Reader) -> Result<VMEventType, SnapshotError> {
+    Ok(match r.u8()? {
+        0 => VMEventType::Start,
+        1 => VMEventType::Stop,
+        2 => VMEventType::Fault(read_fault(r)?),
+        3 => VMEventType::PolicyViolation(read_fault(r)?),
+        4 => VMEventType::OutOfFuel,
+        5 => VMEventType::TimedOut,
+        6 => VMEventType::Syscall(r.usize()?, r.u64()? as u16),
+        7 => VMEventType::HeapGrowth(r.usize()?, r.usize()?, r.usize()?),
+        8 => VMEventType::Breakpoint(r.usize()?),
+        9 => {
+            let pc = r.usize()?;
+            let w = match (r.u64()?, r.usize()?) {
+                (0, reg) => Watch::Reg(reg),
+                (1, addr) => Watch::Heap(addr),
+                _ => return Err(SnapshotError::Invalid("watch")),
+            };
+            let mut value = || -> Result<Option<i32>, SnapshotError> {
+                let (present, v) = (r.u64()?, r.u64()?);
+                Ok((present != 0).then_some(v as u32 as i32))
+            };
+            let (old, new) = (value()?, value()?);
+            VMEventType::Watchpoint(pc, w, old, new)
+        }
+        10 => VMEventType::Interrupted,
+        _ => return Err(SnapshotError::Invalid("event type")),
+    })
+}
+
+impl VM {
+    /// the state of the running program: registers, flags, pc, program and
+    /// its debug info, read-only data, heap, remaining fuel and events. Host setup (syscalls,
+    /// policy, deadline and the other knobs) isn't part of it.
+    ///
+    /// The VM has no stack, everything a program keeps is in the above.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.bytes.extend_from_slice(&SNAPSHOT_PREFIX);
+        w.u16(SNAPSHOT_VERSION);
+        w.bytes.extend_from_slice(self.id.as_bytes());
+        for r in self.regs {
+            w.u32(r as u32);
+        }
+        for f in self.fregs {
+            w.u64(f.to_bits());
+        }
+        w.u64(self.pc as u64);
+        w.u32(self.remainder);
+        w.bool(self.bool_flag);
+        for f in [
+            self.flags.zero,
+            self.flags.negative,
+            self.flags.carry,
+            self.flags.overflow,
+        ] {
+            w.bool(f);
+        }
+        w.bool(self.fuel.is_some());
+        w.u64(self.fuel.unwrap_or(0));
+        w.slice(&self.program);
+        w.bool(self.debug_info.is_some());
+        w.slice(&self.debug_info.as_ref().map_or(vec![], |d| d.to_bytes()));
+        w.slice(&self.ro_data);
+        w.slice(&self.heap);
+        self.allocator.snapshot(&mut w);
+        w.u64(self.events.len() as u64);
+        for e in &self.events {
+            write_event(&mut w, &e.event);
+            w.u64(e.at.timestamp() as u64);
+            w.u32(e.at.timestamp_subsec_nanos());
+            w.bytes.extend_from_slice(e.vm_id.as_bytes());
+        }
+        w.bytes
+    }
+
+    /// replaces the program state with a snapshot, keeping the host setup.
+    /// Nothing changes if the snapshot can't be read.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut r = Reader::new(snapshot);
+        if r.take(SNAPSHOT_PREFIX.len()).ok() != Some(&SNAPSHOT_PREFIX[..]) {
+            return Err(SnapshotError::BadPrefix);
+        }
+        let version = r.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        // read into a scratch VM so a bad snapshot leaves this one alone
+        let mut vm = VM::new();
+        vm.id = uuid::Uuid::from_bytes(r.array()?);
+        for reg in vm.regs.iter_mut() {
+            *reg = r.u32()? as i32;
+        }
+        for freg in vm.fregs.iter_mut() {
+            *freg = f64::from_bits(r.u64()?);
+        }
+        vm.pc = r.usize()?;
+        vm.remainder = r.u32()?;
+        vm.bool_flag = r.bool()?;
+        vm.flags = Flags {
+            zero: r.bool()?,
+            negative: r.bool()?,
+            carry: r.bool()?,
+            overflow: r.bool()?,
+        };
+        let fuel = (r.bool()?, r.u64()?);
+        vm.fuel = fuel.0.then_some(fuel.1);
+        vm.set_program(r.vec()?);
+        let debug_info = (r.bool()?, r.vec()?);
+        if debug_info.0 {
+            vm.debug_info = Some(DebugInfo::parse(&debug_info.1)?);
+        }
+        vm.ro_data = r.vec()?;
+        vm.heap = r.vec()?;
+        vm.allocator = Allocator::restore(&mut r)?;
+        if !vm.allocator.fits(vm.heap.len()) {
+            return Err(SnapshotError::Invalid("heap blocks"));
+        }
+        for _ in 0..r.u64()? {
+            let event = read_event(&mut r)?;
+            let (secs, nanos) = (r.u64()? as i64, r.u32()?);
+            vm.events.push(VMEvent {
+                event,
+                at: DateTime::<Utc>::from_timestamp(secs, nanos)
+                    .ok_or(SnapshotError::Invalid("event time"))?,
+                vm_id: uuid::Uuid::from_bytes(r.array()?),
+            });
+        }
+        if !r.bytes.is_empty() {
+            return Err(SnapshotError::Invalid("trailing bytes"));
+        }
+        // a snapshot can be corrupt or made up, so it gets what `load` checks
+        let code_offset = self
+            .check(&vm.program, &vm.ro_data)
+            .map_err(SnapshotError::Program)?;
+        self.id = vm.id;
+        self.regs = vm.regs;
+        self.fregs = vm.fregs;
+        self.pc = vm.pc;
+        self.remainder = vm.remainder;
+        self.bool_flag = vm.bool_flag;
+        self.flags = vm.flags;
+        self.fuel = vm.fuel;
+        self.set_program(vm.program);
+        self.debug_info = vm.debug_info;
+        self.ro_data = vm.ro_data;
+        self.heap = vm.heap;
+        self.allocator = vm.allocator;
+        self.events = vm.events;
+        self.fault = None;
+        self.decoded = predecode(&self.program, code_offset);
+        // the recorded steps lead to another state
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+    use crate::vm::tests::run;
+    #[test]
+    fn test_snapshot_restore() {
+        let mut vm = VM::new();
+        vm.set_heap_debug(false);
+        vm.set_program(vec![
+            Opcode::LOAD as u8,
+            0,
+            0,
+            8,
+            Opcode::ALOC as u8,
+            0,
+            1,
+            0,
+            Opcode::DEC as u8,
+            0,
+            0,
+            0,
+            Opcode::FREE as u8,
+            1,
+            0,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+        ]);
+        vm.fregs[3] = -1.5;
+        vm.fuel = Some(3);
+        vm.debug_info = Some(DebugInfo {
+            files: vec!["a.asm".to_string()],
+            ..DebugInfo::default()
+        });
+        run(&mut vm);
+        let snapshot = vm.snapshot();
+
+        let mut restored = VM::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.id, vm.id);
+        assert_eq!(restored.regs, vm.regs);
+        assert_eq!(restored.fregs, vm.fregs);
+        assert_eq!((restored.pc, restored.fuel), (12, Some(0)));
+        assert_eq!(restored.flags, vm.flags);
+        assert_eq!(restored.heap, vm.heap);
+        assert_eq!(restored.heap_stats(), vm.heap_stats());
+        assert_eq!(restored.events, vm.events);
+        assert_eq!(restored.debug_info, vm.debug_info);
+        assert_eq!(restored.events[1].event, VMEventType::HeapGrowth(4, 0, 8));
+        assert_eq!(restored.snapshot(), snapshot);
+        // the restored VM carries on where the original stopped
+        restored.fuel = None;
+        vm.fuel = None;
+        run(&mut restored);
+        run(&mut vm);
+        assert_eq!(restored.heap_stats(), vm.heap_stats());
+        assert_eq!(restored.fault, None);
+    }
+    #[test]
+    fn test_restore_errors() {
+        let mut vm = VM::new();
+        let mut snapshot = vm.snapshot();
+        assert_eq!(vm.restore(&snapshot[..20]), Err(SnapshotError::Truncated));
+        assert_eq!(vm.restore(b"PIE"), Err(SnapshotError::BadPrefix));
+        snapshot[4..6].copy_from_slice(&2u16.to_be_bytes());
+        assert_eq!(
+            vm.restore(&snapshot),
+            Err(SnapshotError::UnsupportedVersion(2))
+        );
+        // bytecode set by hand, without padding
+        let mut vm = VM::new();
+        vm.set_program(vec![Opcode::HLT as u8]);
+        let snapshot = vm.snapshot();
+        assert_eq!(VM::new().restore(&snapshot), Ok(()));
+        // a register past $31 in the program
+        let mut vm = VM::new();
+        vm.set_program(vec![Opcode::INC as u8, 32, 0, 0]);
+        let snapshot = vm.snapshot();
+        assert!(matches!(
+            VM::new().restore(&snapshot),
+            Err(SnapshotError::Program(LoadError::Invalid(_)))
+        ));
+    }
+}
@@ -0,0 +1,161 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The part of the VM a host function is allowed to touch. The heap can be
+/// read, written a range at a time, and only resized through `resize_heap`,
+/// which keeps it within the policy and over the allocator's blocks.
+pub struct SyscallContext<'a> {
+    pub regs: &'a mut [i32; 32],
+    heap: &'a mut Vec<u8>,
+    max_heap: Option<usize>,                           // from the policy
+    allocated: usize,                                  // end of the allocator's highest block
+    pub(super) refused: Option<HeapError>,             // faults the VM after the call
+    pub(super) written: Option<Vec<(usize, Vec<u8>)>>, // address, old bytes; while recording
+}
+
+/// Why `resize_heap` refused
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeapError {
+    Limit(usize),     // size asked for, over the policy's max_heap
+    Allocated(usize), // size asked for, under the end of the allocated blocks
+}
+
+/// Ok(value) is written to $0, Err(reason) faults the VM.
+pub type SyscallResult = Result<i32, String>;
+pub type HostFn = Arc<dyn Fn(&mut SyscallContext) -> SyscallResult + Send + Sync>;
+
+#[derive(Clone)]
+pub struct HostFunction {
+    pub id: u16,
+    pub name: String,
+    f: HostFn,
+}
+
+#[derive(Clone, Default)]
+pub struct SyscallTable {
+    functions: Vec<HostFunction>,
+}
+
+impl<'a> SyscallContext<'a> {
+    pub fn new(
+        regs: &'a mut [i32; 32],
+        heap: &'a mut Vec<u8>,
+        max_heap: Option<usize>,
+        allocated: usize,
+    ) -> SyscallContext<'a> {
+        SyscallContext {
+            regs,
+            heap,
+            max_heap,
+            allocated,
+            refused: None,
+            written: None,
+        }
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        self.heap
+    }
+
+    /// the heap bytes in `range` for writing, None if it isn't all inside
+    /// the heap
+    pub fn heap_mut(&mut self, range: Range<usize>) -> Option<&mut [u8]> {
+        let bytes = self.heap.get_mut(range.clone())?;
+        if let Some(written) = self.written.as_mut() {
+            written.push((range.start, bytes.to_vec()));
+        }
+        Some(bytes)
+    }
+
+    /// grows the heap with zeroes or shrinks it to `len` bytes. A refusal
+    /// faults the VM once the host function returns, whatever it returns.
+    pub fn resize_heap(&mut self, len: usize) -> Result<(), HeapError> {
+        let refused = if self.max_heap.is_some_and(|max| len > max) {
+            HeapError::Limit(len)
+        } else if len < self.allocated {
+            HeapError::Allocated(len)
+        } else {
+            if let (Some(written), Some(cut)) = (self.written.as_mut(), self.heap.get(len..)) {
+                written.push((len, cut.to_vec()));
+            }
+            self.heap.resize(len, 0);
+            return Ok(());
+        };
+        self.refused.get_or_insert(refused);
+        Err(refused)
+    }
+}
+
+impl HostFunction {
+    pub fn call(&self, ctx: &mut SyscallContext) -> SyscallResult {
+        (self.f)(ctx)
+    }
+}
+
+impl SyscallTable {
+    pub fn new() -> SyscallTable {
+        SyscallTable { functions: vec![] }
+    }
+
+    /// registers `f` as host function `id`, replacing any previous one
+    pub fn register(&mut self, id: u16, name: &str, f: HostFn) {
+        self.functions.retain(|h| h.id != id);
+        self.functions.push(HostFunction {
+            id,
+            name: name.to_string(),
+            f,
+        });
+    }
+
+    pub fn by_id(&self, id: u16) -> Option<&HostFunction> {
+        self.functions.iter().find(|h| h.id == id)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&HostFunction> {
+        self.functions.iter().find(|h| h.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_syscall_table() {
+        let mut t = SyscallTable::new();
+        t.register(1, "one", Arc::new(|_| Ok(1)));
+        t.register(2, "two", Arc::new(|_| Ok(2)));
+        assert_eq!(t.by_name("two").unwrap().id, 2);
+        assert!(t.by_id(3).is_none());
+        t.register(1, "uno", Arc::new(|_| Ok(11)));
+        assert!(t.by_name("one").is_none());
+        assert_eq!(t.by_id(1).unwrap().name, "uno");
+    }
+    #[test]
+    fn test_heap_mut() {
+        let mut regs = [0; 32];
+        let mut heap = vec![1; 8];
+        let mut ctx = SyscallContext::new(&mut regs, &mut heap, None, 4);
+        ctx.written = Some(vec![]);
+        ctx.heap_mut(2..4).unwrap().fill(7);
+        assert!(ctx.heap_mut(6..9).is_none());
+        assert_eq!(ctx.resize_heap(5), Ok(()));
+        assert_eq!(ctx.heap(), [1, 1, 7, 7, 1]);
+        // what was there before, for reverse debugging
+        assert_eq!(ctx.written, Some(vec![(2, vec![1, 1]), (5, vec![1; 3])]));
+    }
+    #[test]
+    fn test_resize_heap() {
+        let mut regs = [0; 32];
+        let mut heap = vec![1; 8];
+        let mut ctx = SyscallContext::new(&mut regs, &mut heap, Some(16), 4);
+        assert_eq!(ctx.resize_heap(12), Ok(()));
+        assert_eq!(ctx.heap()[8..], [0; 4]);
+        assert_eq!(ctx.resize_heap(4), Ok(()));
+        assert_eq!(ctx.refused, None);
+        assert_eq!(ctx.resize_heap(17), Err(HeapError::Limit(17)));
+        assert_eq!(ctx.resize_heap(3), Err(HeapError::Allocated(3)));
+        // the first refusal is the one reported
+        assert_eq!(ctx.refused, Some(HeapError::Limit(17)));
+        assert_eq!(heap.len(), 4);
+    }
+}
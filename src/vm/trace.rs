@@ -0,0 +1,288 @@
+use std::io::{self, Write};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use super::decode::{Insn, Reg};
+use super::VM;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Json, // one object per line
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "json" => Ok(TraceFormat::Json),
+            _ => Err(format!("unknown trace format {}", s)),
+        }
+    }
+}
+
+/// Logs every instruction `VM::step` runs while the VM has one
+#[derive(Clone)]
+pub struct Tracer {
+    pub format: TraceFormat,
+    pub range: Option<Range<usize>>, // only instructions at these addresses
+    out: Arc<Mutex<dyn Write + Send>>, // shared by the clones of the VM
+}
+
+/// The registers before a step, compared with the ones after it
+pub(super) struct Before {
+    regs: [i32; 32],
+    fregs: [f64; 32],
+}
+
+impl Tracer {
+    pub fn new<W: Write + Send + 'static>(format: TraceFormat, out: W) -> Tracer {
+        Tracer {
+            format,
+            range: None,
+            out: Arc::new(Mutex::new(out)),
+        }
+    }
+
+    pub fn stdout(format: TraceFormat) -> Tracer {
+        Tracer::new(format, io::stdout())
+    }
+
+    pub fn traces(&self, pc: usize) -> bool {
+        self.range.as_ref().is_none_or(|r| r.contains(&pc))
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.out.lock().unwrap().flush()
+    }
+
+    /// one line for the instruction at `pc`, disassembled as `insn`, from
+    /// the source `location` if the program has debug info
+    pub fn line(
+        &self,
+        pc: usize,
+        insn: &str,
+        location: Option<&str>,
+        reads: &[(Reg, f64)],
+        writes: &[(Reg, f64, f64)],
+    ) -> String {
+        match self.format {
+            TraceFormat::Text => {
+                let mut line = format!("{:#06x}  {:<20}", pc, insn);
+                for (r, v) in reads {
+                    line.push_str(&format!(" {}={}", r, v));
+                }
+                if !writes.is_empty() {
+                    line.push_str(" ->");
+                }
+                for (r, old, new) in writes {
+                    line.push_str(&format!(" {}={} (was {})", r, new, old));
+                }
+                if let Some(location) = location {
+                    line.push_str(&format!("  ; {}", location));
+                }
+                line.trim_end().to_string()
+            }
+            TraceFormat::Json => {
+                let reads: Vec<String> = reads
+                    .iter()
+                    .map(|(r, v)| format!("\"{}\":{}", r, json_number(*v)))
+                    .collect();
+                let writes: Vec<String> = writes
+                    .iter()
+                    .map(|(r, old, new)| {
+                        format!("\"{}\":[{},{}]", r, json_number(*old), json_number(*new))
+                    })
+                    .collect();
+                let location = match location {
+                    Some(location) => format!(
+                        ",\"at\":\"{}\"",
+                        location.replace('\\', "\\\\").replace('"', "\\\"")
+                    ),
+                    None => String::new(),
+                };
+                format!(
+                    "{{\"pc\":{},\"insn\":\"{}\",\"reads\":{{{}}},\"writes\":{{{}}}{}}}",
+                    pc,
+                    insn,
+                    reads.join(","),
+                    writes.join(","),
+                    location
+                )
+            }
+        }
+    }
+}
+
+/// `START..END`, with the addresses read by `addr`
+pub fn parse_range(s: &str, addr: impl Fn(&str) -> Option<usize>) -> Option<Range<usize>> {
+    let (start, end) = s.split_once("..")?;
+    Some(addr(start)?..addr(end)?)
+}
+
+/// NaN and infinities aren't JSON numbers
+fn json_number(v: f64) -> String {
+    if v.is_finite() {
+        v.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+impl VM {
+    pub(super) fn before_trace(&self) -> Before {
+        Before {
+            regs: self.regs,
+            fregs: self.fregs,
+        }
+    }
+
+    /// logs the instruction at `pc` that just ran, given the registers before it
+    pub(super) fn trace(&self, pc: usize, insn: &Insn, before: Before) {
+        let tracer = match &self.tracer {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        let mut reads: Vec<(Reg, f64)> = vec![];
+        for r in insn.reads() {
+            if reads.iter().any(|&(seen, _)| seen == r) {
+                continue;
+            }
+            let v = match r {
+                Reg::Int(r) => before.regs[r] as f64,
+                Reg::Float(r) => before.fregs[r],
+            };
+            reads.push((r, v));
+        }
+        let mut writes = vec![];
+        for r in 0..32 {
+            if self.regs[r] != before.regs[r] {
+                writes.push((Reg::Int(r), before.regs[r] as f64, self.regs[r] as f64));
+            }
+        }
+        for r in 0..32 {
+            if self.fregs[r].to_bits() != before.fregs[r].to_bits() {
+                writes.push((Reg::Float(r), before.fregs[r], self.fregs[r]));
+            }
+        }
+        let location = self.location(pc);
+        let line = tracer.line(
+            pc,
+            &self.disassemble(pc),
+            location.as_deref(),
+            &reads,
+            &writes,
+        );
+        // a broken trace shouldn't stop the program
+        let _ = writeln!(tracer.out.lock().unwrap(), "{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+    use crate::vm::tests::run;
+
+    /// collects the trace for the test to look at
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn traced(format: TraceFormat, range: Option<Range<usize>>) -> String {
+        let mut vm = VM::new();
+        // 0: load $1 #5; 4: itof $1 $2; 8: add $1 $1 $3; 12: hlt
+        vm.set_program(vec![
+            Opcode::LOAD as u8,
+            1,
+            0,
+            5,
+            Opcode::ITOF as u8,
+            1,
+            2,
+            0,
+            Opcode::ADD as u8,
+            1,
+            1,
+            3,
+            Opcode::HLT as u8,
+        ]);
+        let buf = Buffer::default();
+        let mut tracer = Tracer::new(format, buf.clone());
+        tracer.range = range;
+        vm.tracer = Some(tracer);
+        run(&mut vm);
+        let out = buf.0.lock().unwrap().clone();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_trace_text() {
+        let out = traced(TraceFormat::Text, None);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "0x0000  load $1 #5           -> $1=5 (was 0)");
+        assert_eq!(
+            lines[1],
+            "0x0004  itof $1 $2           $1=5 -> $f2=5 (was 0)"
+        );
+        assert_eq!(
+            lines[2],
+            "0x0008  add $1 $1 $3         $1=5 -> $3=10 (was 0)"
+        );
+        assert_eq!(lines[3], "0x000c  hlt");
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let addr = |s: &str| s.parse().ok();
+        assert_eq!(parse_range("64..72", addr), Some(64..72));
+        assert_eq!(parse_range("64", addr), None);
+        assert_eq!(parse_range("64..x", addr), None);
+    }
+
+    #[test]
+    fn test_trace_locations() {
+        let mut asm = crate::asm::Assembler::new();
+        asm.debug = true;
+        asm.file = "t.asm".to_string();
+        let program = asm
+            .assemble(".data\n.code\ntop: eq $0 $1\nbne @top\nhlt")
+            .unwrap();
+        let mut vm = VM::new();
+        vm.load(program, vec![]).unwrap();
+        let buf = Buffer::default();
+        vm.tracer = Some(Tracer::new(TraceFormat::Text, buf.clone()));
+        run(&mut vm);
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[1].contains("bne -4 <top>"));
+        assert!(lines[1].ends_with("  ; t.asm:4:1"));
+
+        let tracer = Tracer::new(TraceFormat::Json, io::sink());
+        assert_eq!(
+            tracer.line(8, "hlt", Some("a \"b\".asm:1:1"), &[], &[]),
+            "{\"pc\":8,\"insn\":\"hlt\",\"reads\":{},\"writes\":{},\"at\":\"a \\\"b\\\".asm:1:1\"}"
+        );
+    }
+
+    #[test]
+    fn test_trace_json() {
+        let out = traced(TraceFormat::Json, Some(8..12));
+        assert_eq!(
+            out,
+            "{\"pc\":8,\"insn\":\"add $1 $1 $3\",\"reads\":{\"$1\":5},\"writes\":{\"$3\":[0,10]}}\n"
+        );
+    }
+}
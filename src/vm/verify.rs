@@ -0,0 +1,134 @@
+use super::decode::{decode, DecodeError};
+use crate::instruction::Opcode;
+
+/// Something wrong with the code section, found before running it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Diagnostic {
+    UnknownOpcode(usize, u8),    // where, opcode
+    BadRegister(usize, u8),      // where, register
+    Truncated(usize),            // where
+    BadJumpTarget(usize, usize), // where, target
+    BadRoOffset(usize, usize),   // where, offset
+}
+
+/// checks every instruction of the code starting at `code_offset`: opcodes
+/// must be known, registers < 32, instructions complete, branch targets on
+/// instruction boundaries or the end and ro offsets within `ro_data`
+///
+/// instructions are laid out as `decode` reads them, so the last one may
+/// leave out its padding
+///
+/// targets of jumps through registers are only known when running, so they
+/// aren't checked
+pub fn verify(program: &[u8], code_offset: usize, ro_data: &[u8]) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let is_boundary = |t: usize| {
+        t == program.len()
+            || t >= code_offset && t < program.len() && (t - code_offset).is_multiple_of(4)
+    };
+    let mut pc = code_offset;
+    while pc < program.len() {
+        let insn = match decode(program, pc) {
+            Ok(insn) => insn,
+            Err(DecodeError::BadRegister(r)) => {
+                diagnostics.push(Diagnostic::BadRegister(pc, r));
+                pc += 4;
+                continue;
+            }
+            Err(DecodeError::Truncated) => {
+                diagnostics.push(Diagnostic::Truncated(pc));
+                break;
+            }
+        };
+        match insn.op {
+            Opcode::IGL => diagnostics.push(Diagnostic::UnknownOpcode(pc, program[pc])),
+            Opcode::BR | Opcode::BEQ | Opcode::BNE => {
+                let target = pc.wrapping_add_signed(insn.off() as i16 as isize);
+                if !is_boundary(target) {
+                    diagnostics.push(Diagnostic::BadJumpTarget(pc, target));
+                }
+            }
+            // strings end at a nul byte, so their start must be in ro
+            Opcode::PRTS if insn.off() as usize >= ro_data.len() => {
+                diagnostics.push(Diagnostic::BadRoOffset(pc, insn.off() as usize))
+            }
+            Opcode::LOADF if insn.imm() as usize + 8 > ro_data.len() => {
+                diagnostics.push(Diagnostic::BadRoOffset(pc, insn.imm() as usize))
+            }
+            _ => {}
+        }
+        pc += 4;
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_verify() {
+        let ok = [
+            Opcode::LOAD as u8,
+            1,
+            0,
+            3,
+            Opcode::PRTS as u8,
+            0,
+            0,
+            0,
+            Opcode::BNE as u8,
+            0xff,
+            0xfc,
+            0,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+        ];
+        assert_eq!(verify(&ok, 0, b"hi\0"), vec![]);
+        assert_eq!(verify(&ok, 0, b""), vec![Diagnostic::BadRoOffset(4, 0)]);
+        let bad = [
+            Opcode::ADD as u8,
+            1,
+            40,
+            2,
+            Opcode::BR as u8,
+            0,
+            2,
+            0,
+            Opcode::IGL as u8,
+            0,
+            0,
+            0,
+            Opcode::LOADF as u8,
+            0,
+            0,
+            0,
+            Opcode::LOAD as u8,
+            1,
+        ];
+        assert_eq!(
+            verify(&bad, 0, &[0; 4]),
+            vec![
+                Diagnostic::BadRegister(0, 40),
+                Diagnostic::BadJumpTarget(4, 6),
+                Diagnostic::UnknownOpcode(8, Opcode::IGL as u8),
+                Diagnostic::BadRoOffset(12, 0),
+                Diagnostic::Truncated(16),
+            ]
+        );
+    }
+    #[test]
+    fn test_verify_end() {
+        // HLT stops one byte in, like `decode` reads it
+        assert_eq!(verify(&[Opcode::HLT as u8], 0, b""), vec![]);
+        // a branch to the end of the program ends it
+        let program = [Opcode::BR as u8, 0, 4, 0];
+        assert_eq!(verify(&program, 0, b""), vec![]);
+        let program = [Opcode::BR as u8, 0, 8, 0];
+        assert_eq!(
+            verify(&program, 0, b""),
+            vec![Diagnostic::BadJumpTarget(0, 8)]
+        );
+    }
+}